@@ -0,0 +1,197 @@
+use crate::event::{Enricher, Event, User};
+use crate::lua;
+use crate::signup::engine::{RuleEngine, RuleOutcome};
+use crate::signup::rulefile;
+use crate::signup::rules::{validate_rules, Rule};
+
+use chrono::Utc;
+use std::fs;
+
+const USAGE: &str = "Usage:
+  lichess-event-stream                      run the bot
+  lichess-event-stream validate <file>      check a rules file (.toml or .json)
+  lichess-event-stream eval <file> <users> [--geoip <db.mmdb> --uap <regexes.yaml>]
+                                            show which rules would fire on a user JSON
+                                            or on an NDJSON file of signups";
+
+/// Runs the offline subcommands. Returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    match args.first().map(|a| a.as_str()) {
+        Some("validate") => match args.get(1) {
+            Some(path) => validate(path),
+            None => usage(),
+        },
+        Some("eval") => match (args.get(1), args.get(2)) {
+            (Some(rules_path), Some(users_path)) => {
+                match (option(args, "--geoip"), option(args, "--uap")) {
+                    (Ok(geoip), Ok(uap)) if geoip.is_some() == uap.is_some() => {
+                        eval(rules_path, users_path, geoip, uap)
+                    }
+                    _ => usage(),
+                }
+            }
+            _ => usage(),
+        },
+        _ => usage(),
+    }
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    2
+}
+
+/// The value after `name`, if the option is given. An option without a value is an error.
+fn option<'a>(args: &'a [String], name: &str) -> Result<Option<&'a str>, ()> {
+    match args.iter().position(|a| a == name) {
+        Some(i) => args.get(i + 1).map(|v| Some(v.as_str())).ok_or(()),
+        None => Ok(None),
+    }
+}
+
+/// Prints every problem in the rules file as `path:line: message`, for editors and hooks.
+fn validate(path: &str) -> i32 {
+    match load_rules(path, &lua::new_lua()) {
+        Ok(_) => {
+            println!("{}: OK", path);
+            0
+        }
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            1
+        }
+    }
+}
+
+/// Reads and validates a rules file, `.toml` or `.json`.
+fn load_rules(path: &str, lua_state: &rlua::Lua) -> Result<Vec<Rule>, Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| vec![format!("{}: {}", path, e)])?;
+    if path.ends_with(".toml") {
+        let errors: Vec<String> = rulefile::validate(&text, lua_state)
+            .iter()
+            .map(|e| match e.line {
+                Some(line) => format!("{}:{}: {}", path, line, e.message),
+                None => format!("{}: {}", path, e.message),
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        rulefile::from_toml(&text).map_err(|e| vec![format!("{}: {}", path, e)])
+    } else {
+        let rules = serde_json::from_str::<Vec<Rule>>(&text)
+            .map_err(|e| vec![format!("{}:{}: {}", path, e.line(), e)])?;
+        validate_rules(&rules, lua_state).map_err(|e| vec![format!("{}: {}", path, e)])?;
+        Ok(rules)
+    }
+}
+
+/// Reads a single user JSON, or one signup per line, either as a `User` or as a `signup`
+/// event from the stream.
+fn load_users(path: &str) -> Result<Vec<User>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    if let Ok(user) = User::from_json(&text) {
+        return Ok(vec![user]);
+    }
+    let mut users = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let user = match Event::from_json(line) {
+            Ok(Event::Signup(user)) => user,
+            _ => User::from_json(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?,
+        };
+        users.push(user);
+    }
+    Ok(users)
+}
+
+/// Evaluates the rules on each user the way `signup rules test` does, with the actions of a
+/// first match since there is no escalation history offline, and without `hourly_cap`
+/// since nothing is taken. The output says so up front. Users are enriched only if
+/// both the GeoIP database and the UA regexes are given; otherwise their `geoip` and
+/// `device` are used as they are in the file.
+fn eval(
+    rules_path: &str,
+    users_path: &str,
+    geoip_db_path: Option<&str>,
+    uap_regexes_path: Option<&str>,
+) -> i32 {
+    let lua_state = lua::new_lua();
+    let rules = match load_rules(rules_path, &lua_state) {
+        Ok(rules) => rules,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            return 1;
+        }
+    };
+    let users = match load_users(users_path) {
+        Ok(users) => users,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let enricher = match (geoip_db_path, uap_regexes_path) {
+        (Some(geoip_db_path), Some(uap_regexes_path)) => {
+            match Enricher::open(geoip_db_path, uap_regexes_path) {
+                Ok(enricher) => Some(enricher),
+                Err(e) => {
+                    eprintln!("Could not load GeoIP or UA data: {}", e);
+                    return 1;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    println!(
+        "Showing the actions of a first match: escalation steps and `hourly_cap` are not \
+         applied offline."
+    );
+    let engine = RuleEngine::new(&lua_state);
+    let now = Utc::now();
+    let mut failed = false;
+    for mut user in users {
+        if let Some(ref enricher) = enricher {
+            if let Err(e) = enricher.enrich(&mut user) {
+                eprintln!("{}: {}", &user.username.0, e);
+            }
+        }
+        let evaluation = engine.evaluate(&rules, &user, now, |_, _| 1);
+        for outcome in &evaluation.outcomes {
+            match outcome {
+                RuleOutcome::Match(planned) => println!(
+                    "{}: rule {} would take these actions: {:?}",
+                    &user.username.0, &planned.rule.name, &planned.actions
+                ),
+                RuleOutcome::Error { rule, error } => {
+                    failed = true;
+                    eprintln!(
+                        "{}: error on rule {}: {}",
+                        &user.username.0, &rule.name, error
+                    );
+                }
+            }
+        }
+        if let Some(rule) = evaluation.stopped_by {
+            println!(
+                "{}: rule {} stops the evaluation of further rules.",
+                &user.username.0, &rule.name
+            );
+        }
+        if evaluation.matches().next().is_none() {
+            println!("{}: no rule matches", &user.username.0);
+        }
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
@@ -1,10 +1,13 @@
-use crate::signup::rules::Rule;
+use crate::signup::audit::AuditQuery;
+use crate::signup::bundle::ImportMode;
+use crate::signup::rules::{Action, ActionOutcome, Rule, RuleEdit, RuleSelection};
 
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use maxminddb::geoip2;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use uaparser::{Parser, UserAgentParser};
 
 #[derive(Deserialize, Clone)]
@@ -15,19 +18,77 @@ pub enum Event {
     InternalHypotheticalSignup(User),
     InternalAddRule {
         rule: Rule,
+        author: String,
     },
     InternalShowRule(String),
-    InternalRemoveRule(String),
-    InternalDisableRules(String),
-    InternalEnableRules(String),
-    InternalListRules,
+    InternalRemoveRule {
+        name: String,
+        author: String,
+    },
+    InternalEditRule {
+        name: String,
+        edit: RuleEdit,
+        author: String,
+    },
+    InternalDisableRules {
+        selection: RuleSelection,
+        author: String,
+    },
+    InternalEnableRules {
+        selection: RuleSelection,
+        author: String,
+    },
+    InternalRemoveTag {
+        tag: String,
+        author: String,
+    },
+    InternalRuleHistory(String),
+    InternalRestoreRule {
+        name: String,
+        revision: u32,
+        author: String,
+    },
+    InternalListRules(Option<String>),
     InternalStreamEventReceived,
     InternalZulipStatusCommand,
     InternalIsRecentlyChecked(String),
     InternalCheckRulesExpiry,
+    InternalCheckStaleRules,
+    InternalCheckRulesFile,
+    InternalReloadRules,
+    InternalExportRules(Option<RuleSelection>),
+    InternalImportRules {
+        rules: Vec<Rule>,
+        mode: ImportMode,
+        author: String,
+    },
     InternalRenewRule {
         rule: String,
         new_expiry: DateTime<Utc>,
+        author: String,
+    },
+    InternalRenewTag {
+        tag: String,
+        new_expiry: DateTime<Utc>,
+        author: String,
+    },
+    InternalRunDueActions,
+    InternalListQueue,
+    InternalCancelQueued(String),
+    InternalResetBreaker,
+    InternalActionOutcome {
+        rule: String,
+        username: Username,
+        action: Action,
+        revert: bool,
+        outcome: ActionOutcome,
+    },
+    InternalAuditSearch(AuditQuery),
+    InternalAuditExport(AuditQuery),
+    InternalRevertRule {
+        rule: String,
+        since: Option<DateTime<Utc>>,
+        confirmed: bool,
     },
 }
 
@@ -37,17 +98,24 @@ impl Event {
     }
 }
 
+/// A signup, as sent by the Lichess event stream. `geoip` and `device` are filled in by
+/// [`Enricher`].
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     pub username: Username,
     pub email: Email,
+    /// The IP address the account was created from.
     pub ip: Ip,
     pub user_agent: Option<UserAgent>,
+    /// The browser fingerprint, if Lichess got one.
     pub finger_print: Option<FingerPrint>,
+    /// Whether Lichess considers the IP suspicious, e.g. a proxy. `susp_ip` rules need it.
     #[serde(default = "default_susp_ip")]
     pub susp_ip: bool,
+    /// Filled in by [`Enricher::enrich`]; not part of the stream.
     pub geoip: Option<GeoipInfo>,
+    /// Filled in by [`Enricher::enrich`] from the user agent; not part of the stream.
     pub device: Option<DeviceInfo>,
 }
 
@@ -61,10 +129,53 @@ fn default_susp_ip() -> bool {
     false
 }
 
+/// Fills in the `geoip` and `device` of signups before rules see them.
+pub struct Enricher {
+    geoip_reader: maxminddb::Reader<Vec<u8>>,
+    ua_parser: UserAgentParser,
+}
+
+impl Enricher {
+    pub fn open(
+        geoip_db_path: &str,
+        uap_regexes_path: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Enricher {
+            geoip_reader: maxminddb::Reader::open_readfile(geoip_db_path)?,
+            ua_parser: UserAgentParser::from_yaml(uap_regexes_path)
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))?,
+        })
+    }
+
+    /// The device is set even if the GeoIP lookup fails.
+    pub fn enrich(&self, user: &mut User) -> Result<(), String> {
+        if let Some(ref ua) = user.user_agent {
+            user.device = Some(DeviceInfo::parse_user_agent(&ua.0, &self.ua_parser));
+        }
+        match user.ip.0.parse::<IpAddr>() {
+            Ok(ip) => match self.geoip_reader.lookup::<geoip2::City>(ip) {
+                Ok(city) => {
+                    user.geoip = Some(GeoipInfo::from_maxminddb_city(city));
+                    Ok(())
+                }
+                Err(e) => Err(format!("Error reading GeoIP database: {}", e)),
+            },
+            Err(e) => Err(format!(
+                "Error parsing IP address ({}) for GeoIP: {}",
+                user.ip.0, e
+            )),
+        }
+    }
+}
+
+/// Where a signup's IP address is, according to a MaxMind GeoIP2 City database.
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct GeoipInfo {
+    /// The English name of the country, not its code.
     pub country: Option<String>,
+    /// The English name of the city.
     pub city: Option<String>,
+    /// The English names of the regions, largest first, such as a state and a county.
     pub subdivisions: Option<Vec<String>>,
 }
 
@@ -93,10 +204,14 @@ lazy_static! {
     static ref MOB_UA_TRIM_RE: Regex = Regex::new(r"LM/(\S+) (Android|iOS)/(\S+) (.*)").unwrap();
 }
 
+/// The device, OS and client a signup's user agent points to.
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct DeviceInfo {
+    /// The device model, or `Computer` if the user agent names none.
     pub device: String,
+    /// The OS and its version, e.g. `Android 14`.
     pub os: String,
+    /// The browser or app with its version, e.g. `Firefox 128`.
     pub client: String,
 }
 
@@ -116,10 +231,10 @@ impl DeviceInfo {
     pub fn lichess_mob(ua: &str) -> Option<DeviceInfo> {
         let maybe_caps = MOB_UA_RE.captures(ua);
         maybe_caps.map(|caps| {
-            let version = caps.get(0).map(|m| m.as_str()).unwrap_or("?");
-            let os_name = caps.get(3).map(|m| m.as_str()).unwrap_or("?");
-            let os_version = caps.get(4).map(|m| m.as_str()).unwrap_or("?");
-            let device = caps.get(5).map(|m| m.as_str()).unwrap_or("?");
+            let version = caps.get(1).map(|m| m.as_str()).unwrap_or("?");
+            let os_name = caps.get(4).map(|m| m.as_str()).unwrap_or("?");
+            let os_version = caps.get(5).map(|m| m.as_str()).unwrap_or("?");
+            let device = caps.get(6).map(|m| m.as_str()).unwrap_or("?");
 
             DeviceInfo {
                 device: device.to_string(),
@@ -132,10 +247,10 @@ impl DeviceInfo {
     pub fn lichess_mob_trim(ua: &str) -> Option<DeviceInfo> {
         let maybe_caps = MOB_UA_TRIM_RE.captures(ua);
         maybe_caps.map(|caps| {
-            let version = caps.get(0).map(|m| m.as_str()).unwrap_or("?");
-            let os_name = caps.get(1).map(|m| m.as_str()).unwrap_or("?");
-            let os_version = caps.get(2).map(|m| m.as_str()).unwrap_or("?");
-            let device = caps.get(3).map(|m| m.as_str()).unwrap_or("?");
+            let version = caps.get(1).map(|m| m.as_str()).unwrap_or("?");
+            let os_name = caps.get(2).map(|m| m.as_str()).unwrap_or("?");
+            let os_version = caps.get(3).map(|m| m.as_str()).unwrap_or("?");
+            let device = caps.get(4).map(|m| m.as_str()).unwrap_or("?");
 
             DeviceInfo {
                 device: device.to_string(),
@@ -166,6 +281,7 @@ impl DeviceInfo {
         }
     }
 
+    /// Recognizes the Lichess apps and lichess-bot first, then falls back to ua-parser.
     pub fn parse_user_agent(ua: &str, parser: &UserAgentParser) -> DeviceInfo {
         DeviceInfo::lichess_bot(ua)
             .or_else(|| DeviceInfo::lichess_mob(ua))
@@ -191,3 +307,70 @@ pub struct UserAgent(pub String);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct FingerPrint(pub String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(device: &DeviceInfo) -> (&str, &str, &str) {
+        (&device.device, &device.os, &device.client)
+    }
+
+    #[test]
+    fn recognizes_lichess_bot() {
+        let device = DeviceInfo::lichess_bot("lichess-bot/1.2.0 user:somebot").unwrap();
+        assert_eq!(fields(&device), ("Computer", "Other", "lichess-bot 1.2.0"));
+        assert!(DeviceInfo::lichess_bot("Mozilla/5.0 lichess-bot/1.2.0").is_none());
+    }
+
+    #[test]
+    fn recognizes_lichess_mobile() {
+        let device = DeviceInfo::lichess_mob(
+            "Lichess Mobile/0.9.2 as:anon sri:abcd os:Android/14 dev:Pixel 7",
+        )
+        .unwrap();
+        assert_eq!(
+            fields(&device),
+            ("Pixel 7", "Android 14", "Lichess Mobile 0.9.2")
+        );
+        let device = DeviceInfo::lichess_mob_trim("LM/0.9.2 iOS/17.4 iPhone 13").unwrap();
+        assert_eq!(
+            fields(&device),
+            ("iPhone 13", "iOS 17.4", "Lichess Mobile 0.9.2")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_ua_parser() {
+        let parser = UserAgentParser::from_bytes(
+            br#"
+user_agent_parsers:
+  - regex: '(Firefox)/(\d+)'
+os_parsers:
+  - regex: '(Linux)'
+device_parsers: []
+"#,
+        )
+        .unwrap();
+        let device = DeviceInfo::parse_user_agent(
+            "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+            &parser,
+        );
+        assert_eq!(fields(&device), ("Computer", "Linux", "Firefox 128"));
+        let device = DeviceInfo::parse_user_agent("LM/0.9.2 Android/14 Pixel 7", &parser);
+        assert_eq!(fields(&device).2, "Lichess Mobile 0.9.2");
+    }
+
+    #[test]
+    fn reads_users_with_optional_fields() {
+        let user = User::from_json(
+            r#"{"username": "a", "email": "a@example.com", "ip": "10.0.0.1",
+                "userAgent": "LM/0.9.2 Android/14 Pixel 7", "fingerPrint": null,
+                "geoip": null, "device": null}"#,
+        )
+        .unwrap();
+        assert!(!user.susp_ip);
+        assert!(user.user_agent.map(|ua| ua.0) == Some("LM/0.9.2 Android/14 Pixel 7".to_owned()));
+        assert!(user.finger_print.is_none());
+    }
+}
@@ -1,31 +1,46 @@
 use crate::event::Event;
-use crate::event::{DeviceInfo, GeoipInfo, User};
+use crate::event::{Enricher, User};
 use crate::lua;
+use crate::modaction;
+use crate::signup::actionlog::{ActionLog, ActionLogEntry};
+use crate::signup::audit::{self, AuditEntry, AuditLog, AuditRecord};
+use crate::signup::bundle::RuleBundle;
+use crate::signup::engine::{PlannedMatch, RuleEngine, RuleOutcome};
+use crate::signup::escalation::MatchHistory;
+use crate::signup::limiter::{ActionLimiter, LimitDecision};
+use crate::signup::queue::{self, ActionQueue};
 use crate::signup::rules::Action;
 use crate::signup::rules::*;
+use crate::signup::storage::Storage;
 use crate::zulip;
 
 use chrono::{prelude::*, Duration};
-use futures::future;
-use hyper::header::HeaderValue;
-use hyper::rt::Future;
-use hyper::{Body, Client, Method, Request};
-use hyper_rustls::HttpsConnector;
-use maxminddb::geoip2;
 use rand::{thread_rng, Rng};
 use std::collections::{HashMap, VecDeque};
-use std::net::IpAddr;
+use std::fs;
 use std::ops::Add;
-use std::sync::mpsc::Receiver;
-use std::thread;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, Sender};
 use std::time;
-use tokio;
-use uaparser::UserAgentParser;
+
+const RECENTLY_CHECKED_SIZE: usize = 10000;
 
 pub fn handle_events(
     rx: Receiver<Event>,
+    tx: Sender<Event>,
     token: &'static str,
-    rules_path: &'static str,
+    webhook_secret: &'static str,
+    storage: Rc<dyn Storage>,
+    rule_history_path: &'static str,
+    action_queue_path: &'static str,
+    action_limiter_path: &'static str,
+    action_log_path: &'static str,
+    escalation_history_path: &'static str,
+    global_actions_per_hour: usize,
+    rule_actions_per_hour: usize,
+    stale_rule_days: i64,
+    stale_rule_grace_days: Option<i64>,
+    stale_digest_path: &'static str,
     geoip_db_path: &'static str,
     uap_regexes_path: &'static str,
     zulip_bot_id: &'static str,
@@ -38,24 +53,78 @@ pub fn handle_events(
     zulip_log_topic: &'static str,
     zulip_url: &'static str,
 ) {
-    let mut rule_manager =
-        SignupRulesManager::new(rules_path.to_string()).expect("could not load rules");
+    let mut rule_manager = SignupRulesManager::new(storage.clone(), rule_history_path.to_string())
+        .expect("could not load rules");
+    if let Some(warning) = rule_manager.load_warning() {
+        println!("{}", warning);
+        zulip::web::post_message(
+            warning.to_owned(),
+            zulip_bot_id,
+            zulip_bot_token,
+            zulip_notify_stream,
+            zulip_notify_topic,
+            zulip_url,
+        );
+    }
+
+    let enricher =
+        Enricher::open(geoip_db_path, uap_regexes_path).expect("could not load GeoIP or UA data");
 
-    let geoip_reader =
-        maxminddb::Reader::open_readfile(geoip_db_path).expect("could not load geoip database");
+    let mut action_queue =
+        ActionQueue::new(action_queue_path.to_string()).expect("could not load action queue");
 
-    let ua_parser =
-        UserAgentParser::from_yaml(uap_regexes_path).expect("could not construct UA parser");
+    let mut action_log =
+        ActionLog::new(action_log_path.to_string()).expect("could not load action log");
 
-    println!("Currently {} rules.", rule_manager.rules.len());
+    let audit_log = AuditLog::new(storage.clone());
+
+    let mut match_history = MatchHistory::new(escalation_history_path.to_string())
+        .expect("could not load escalation history");
+
+    println!("Currently {} rules.", rule_manager.rules().len());
+    println!("Currently {} queued actions.", action_queue.actions.len());
 
     let mut latest_event_utc: DateTime<Utc> = Utc::now();
+    let mut latest_stale_digest: Option<NaiveDate> = fs::read_to_string(stale_digest_path)
+        .ok()
+        .and_then(|date| NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok());
 
     let lua_state = lua::new_lua();
 
+    let mut action_limiter = ActionLimiter::new(
+        global_actions_per_hour,
+        rule_actions_per_hour,
+        action_limiter_path.to_string(),
+    )
+    .expect("could not load action limiter");
+
+    let dispatch_settings = DispatchSettings {
+        token,
+        webhook_secret,
+        zulip_bot_id,
+        zulip_bot_token,
+        zulip_notify_stream,
+        zulip_notify_topic,
+        zulip_log_stream,
+        zulip_log_topic,
+        zulip_url,
+    };
     let mut recently_notified: VecDeque<String> = VecDeque::new();
     let mut recently_checked: VecDeque<String> = VecDeque::new();
     let mut recently_checked_info: HashMap<String, VecDeque<User>> = HashMap::new();
+    match storage.recent_signups(RECENTLY_CHECKED_SIZE) {
+        Ok(users) => {
+            for user in users {
+                let user_id = user.username.0.to_lowercase();
+                recently_checked.push_back(user_id.clone());
+                recently_checked_info
+                    .entry(user_id)
+                    .or_default()
+                    .push_back(user);
+            }
+        }
+        Err(e) => println!("Error in .recent_signups: {}", e),
+    }
 
     loop {
         let event = rx.recv().unwrap();
@@ -70,21 +139,8 @@ pub fn handle_events(
                 };
 
                 let mut user = user;
-                let parsed_ip = user.ip.0.parse::<IpAddr>();
-                match parsed_ip {
-                    Ok(ip) => match geoip_reader.lookup::<geoip2::City>(ip) {
-                        Ok(city) => {
-                            user.geoip = Some(GeoipInfo::from_maxminddb_city(city));
-                        }
-                        Err(e) => {
-                            println!("Error reading GeoIP database: {}", e);
-                        }
-                    },
-                    Err(e) => println!("Error parsing IP address ({}) for GeoIP: {}", user.ip.0, e),
-                };
-
-                if let Some(ref ua) = user.user_agent {
-                    user.device = Some(DeviceInfo::parse_user_agent(&ua.0, &ua_parser));
+                if let Err(e) = enricher.enrich(&mut user) {
+                    println!("{}", e);
                 }
                 let user = user;
 
@@ -99,7 +155,13 @@ pub fn handle_events(
                     .unwrap()
                     .push_back(user.clone());
 
-                if recently_checked.len() > 10000 {
+                if !hypothetical {
+                    if let Err(e) = storage.record_signup(&user, RECENTLY_CHECKED_SIZE) {
+                        println!("Error in .record_signup: {}", e);
+                    }
+                }
+
+                if recently_checked.len() > RECENTLY_CHECKED_SIZE {
                     let popped = recently_checked.pop_front().unwrap();
                     recently_checked_info
                         .get_mut(&popped)
@@ -112,153 +174,70 @@ pub fn handle_events(
                     }
                 }
 
-                let delay_ms_if_needed = thread_rng().gen_range(30..100) * 1000;
+                let delay_roll: f64 = thread_rng().gen();
 
                 let mut matched_rules: Vec<String> = vec![];
+                let mut rules_over_cap: Vec<(String, usize)> = vec![];
 
-                for rule in &rule_manager.rules {
-                    let take_action = if !rule.enabled || rule.has_expired() {
-                        Ok(false)
-                    } else if rule.susp_ip && !user.susp_ip {
-                        Ok(false)
-                    } else {
-                        rule.criterion.take_action(&user, &lua_state)
-                    };
-
-                    if hypothetical && take_action.clone().unwrap_or(false) {
-                        zulip::web::post_message(
-                            format!(
-                                "Rule {} would take these actions: {:?}",
-                                &rule.name, &rule.actions
-                            ),
-                            zulip_bot_id,
-                            zulip_bot_token,
-                            zulip_command_stream,
-                            zulip_command_topic,
-                            zulip_url,
-                        );
-                    }
-                    let take_real_action = if take_action.is_ok() {
-                        Ok(take_action.unwrap() && !hypothetical)
-                    } else {
-                        take_action
-                    };
-
-                    match take_real_action {
-                        Ok(true) => {
-                            matched_rules.push(rule.name.clone());
-
-                            let bearer = "Bearer ".to_owned() + token;
-
-                            for action in &rule.actions {
-                                match action.api_endpoint(&user.username) {
-                                    Some(endpoint) => {
-                                        let mut action_req = Request::new(Body::from(""));
-                                        *action_req.uri_mut() = endpoint.parse().unwrap();
-                                        *action_req.method_mut() = Method::POST;
-                                        action_req.headers_mut().insert(
-                                            hyper::header::AUTHORIZATION,
-                                            HeaderValue::from_str(&bearer).unwrap(),
-                                        );
-
-                                        let https = HttpsConnector::new(1);
-                                        let client = Client::builder().build::<_, Body>(https);
-
-                                        let delay = !rule.no_delay
-                                            && (action.eq(&Action::EngineMark)
-                                                || action.eq(&Action::BoostMark)
-                                                || action.eq(&Action::IpBan)
-                                                || action.eq(&Action::Close));
-
-                                        let delay_additional =
-                                            if !rule.no_delay && action.eq(&Action::Close) {
-                                                1500
-                                            } else {
-                                                0
-                                            };
-
-                                        tokio::spawn(future::lazy(move || {
-                                            if delay {
-                                                thread::sleep(time::Duration::from_millis(
-                                                    delay_ms_if_needed + delay_additional,
-                                                ));
-                                            }
-
-                                            client
-                                                .request(action_req)
-                                                .map(|res| println!("Action: {}.", res.status()))
-                                                .map_err(|err| {
-                                                    println!("Error on mod action: {}", err);
-                                                })
-                                        }));
-                                    }
-                                    None => {
-                                        if action.eq(&Action::NotifyZulip)
-                                            && !recently_notified.contains(&user_id)
-                                        {
-                                            zulip::web::post_message(
-                                                format!(
-                                                    "Rule {} match: [{}](https://lichess.org/@/{}?mod)",
-                                                    &rule.name, &user.username.0, &user_id
-                                                ),
-                                                zulip_bot_id,
-                                                zulip_bot_token,
-                                                zulip_notify_stream,
-                                                zulip_notify_topic,
-                                                zulip_url,
-                                            );
-
-                                            recently_notified.push_back(user_id.clone());
-                                            if recently_notified.len() > 2000 {
-                                                recently_notified.pop_front();
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                let evaluation = RuleEngine::new(&lua_state).evaluate(
+                    rule_manager.rules(),
+                    &user,
+                    Utc::now(),
+                    |rule, key| match rule.escalation.as_ref().map(|e| {
+                        match_history.count(
+                            &rule.name,
+                            key,
+                            e.window_days,
+                            Utc::now(),
+                            !hypothetical,
+                        )
+                    }) {
+                        Some(Ok(count)) => count,
+                        Some(Err(e)) => {
+                            println!("Error in .count: {}", e);
+                            1
+                        }
+                        None => 1,
+                    },
+                );
 
-                            if rule.actions.len() > 1
-                                || !rule.actions.get(0).eq(&Some(&Action::NotifyZulip))
-                            {
-                                zulip::web::post_message(
-                                    format!(
-                                        "Rule {} match: \
-                                         {} on [{}](https://lichess.org/@/{}?mod). \
-                                         {} previous matches. \
-                                         Recent matches: {}",
-                                        &rule.name,
-                                        &rule.criterion.friendly(),
-                                        &user.username.0,
-                                        &user.username.0,
-                                        &rule.match_count,
-                                        if rule.most_recent_caught.len() == 0 {
-                                            "None".to_string()
-                                        } else {
-                                            rule.most_recent_caught
-                                                .iter()
-                                                .map(|u| {
-                                                    format!(
-                                                        "[{}](https://lichess.org/@/{}?mod)",
-                                                        &u, &u
-                                                    )
-                                                })
-                                                .collect::<Vec<String>>()
-                                                .join(", ")
-                                        }
-                                    ),
-                                    zulip_bot_id,
-                                    zulip_bot_token,
-                                    zulip_log_stream,
-                                    zulip_log_topic,
-                                    zulip_url,
-                                );
+                for outcome in &evaluation.outcomes {
+                    match outcome {
+                        RuleOutcome::Match(planned) if hypothetical => {
+                            zulip::web::post_message(
+                                format!(
+                                    "Rule {} would take these actions: {:?}",
+                                    &planned.rule.name, &planned.actions
+                                ),
+                                zulip_bot_id,
+                                zulip_bot_token,
+                                zulip_command_stream,
+                                zulip_command_topic,
+                                zulip_url,
+                            );
+                        }
+                        RuleOutcome::Match(planned) => {
+                            matched_rules.push(planned.rule.name.clone());
+                            if let Some(cap) = dispatch(
+                                planned,
+                                &user,
+                                delay_roll,
+                                Dispatch {
+                                    audit_log: &audit_log,
+                                    action_limiter: &mut action_limiter,
+                                    action_queue: &mut action_queue,
+                                    recently_notified: &mut recently_notified,
+                                    tx: &tx,
+                                    settings: &dispatch_settings,
+                                },
+                            ) {
+                                rules_over_cap.push((planned.rule.name.clone(), cap));
                             }
                         }
-                        Ok(false) => {}
-                        Err(err) => {
+                        RuleOutcome::Error { rule, error } => {
                             let err_msg = format!(
                                 "Error on `{}` for user `{}` (probably in Lua snippet): `{}`",
-                                &rule.name, &user.username.0, err
+                                &rule.name, &user.username.0, error
                             );
                             println!("{}", err_msg.clone());
                             zulip::web::post_message(
@@ -273,6 +252,42 @@ pub fn handle_events(
                     }
                 }
 
+                if let (true, Some(rule)) = (hypothetical, evaluation.stopped_by) {
+                    zulip::web::post_message(
+                        format!("Rule {} stops the evaluation of further rules.", &rule.name),
+                        zulip_bot_id,
+                        zulip_bot_token,
+                        zulip_command_stream,
+                        zulip_command_topic,
+                        zulip_url,
+                    );
+                }
+
+                for (name, cap) in rules_over_cap {
+                    let disabled = rule_manager.disable_rule(&name, "circuit breaker");
+                    let cancelled = action_queue.cancel_rule(&name);
+                    zulip::web::post_message(
+                        match (disabled, cancelled) {
+                            (Ok(_), Ok(cancelled)) => format!(
+                                "**Rule `{}` disabled**: it exceeded its cap of {} destructive actions per hour. \
+                                 {} queued actions were cancelled. \
+                                 Review the rule and re-enable it with `signup rules enable-re ^{}$`.",
+                                &name, cap, cancelled, &name
+                            ),
+                            (Err(e), _) | (_, Err(e)) => format!(
+                                "Rule `{}` exceeded its cap of {} destructive actions per hour, \
+                                 but could not be disabled: {:?}",
+                                &name, cap, e
+                            ),
+                        },
+                        zulip_bot_id,
+                        zulip_bot_token,
+                        zulip_notify_stream,
+                        zulip_notify_topic,
+                        zulip_url,
+                    );
+                }
+
                 if !hypothetical {
                     for name in matched_rules {
                         match rule_manager.caught(name, &user.username) {
@@ -282,7 +297,7 @@ pub fn handle_events(
                     }
                 }
             }
-            Event::InternalAddRule { rule } => match rule_manager.add_rule(rule) {
+            Event::InternalAddRule { rule, author } => match rule_manager.add_rule(rule, &author) {
                 Err(err) => {
                     println!("Error on .add_rule: {}", err);
                     zulip::web::post_message(
@@ -309,19 +324,52 @@ pub fn handle_events(
                 let zulip_message = match rule_manager.find_rule(name) {
                     None => "No such rule found.".to_owned(),
                     Some(rule) => format!(
-                        "Created at: {}. Latest match: {}.\nCriterion: {}.\nActions: {:?}{}{}",
+                        "Created at: {} by {}. Revision: {}. Priority: {}{}. Latest match: {}.{}{}\nCriterion: {}.\nActions: {:?}. {}{}{}{}{}",
                         rule.creation_date,
+                        rule.created_by.as_deref().unwrap_or("unknown"),
+                        rule.revision,
+                        rule.priority,
+                        if rule.stop { ", stops further rules" } else { "" },
                         rule.latest_match_date
                             .map(|d| d.to_string())
                             .unwrap_or("Never".to_owned()),
+                        if let Some(ref note) = rule.note {
+                            format!("\nNote: {}", note)
+                        } else {
+                            "".to_owned()
+                        },
+                        if rule.tags.is_empty() {
+                            "".to_owned()
+                        } else {
+                            format!("\nTags: {}", rule.tags.join(", "))
+                        },
                         rule.criterion.friendly(),
                         rule.actions,
-                        if rule.no_delay { ". No delay" } else { "" },
-                        if let Some(expiry) = rule.expiry {
-                            format!(". Expires: {}", expiry)
+                        rule.friendly_delays(),
+                        if let Some(cap) = rule.hourly_cap {
+                            format!(". Hourly cap: {}", cap)
+                        } else {
+                            "".to_owned()
+                        },
+                        if let Some(ref escalation) = rule.escalation {
+                            format!(". {}", escalation.friendly())
                         } else {
                             "".to_owned()
                         },
+                        if let Some(ref schedule) = rule.schedule {
+                            format!(". {}", schedule.friendly())
+                        } else {
+                            "".to_owned()
+                        },
+                        match (rule.expiry, rule.auto_renew) {
+                            (Some(expiry), Some(period)) => format!(
+                                ". Expires: {} (renewed to {} hours after each match)",
+                                expiry,
+                                period / 3600
+                            ),
+                            (Some(expiry), None) => format!(". Expires: {}", expiry),
+                            _ => "".to_owned(),
+                        },
                     ),
                 };
                 zulip::web::post_message(
@@ -333,9 +381,30 @@ pub fn handle_events(
                     zulip_url,
                 );
             }
-            Event::InternalRemoveRule(name) => {
-                let zulip_message = match rule_manager.remove_rule(name) {
+            Event::InternalEditRule { name, edit, author } => {
+                let zulip_message = match rule_manager.edit_rule(&name, edit, &author) {
+                    Ok(true) => "Rule edited!".to_owned(),
+                    Ok(false) => "No such rule found.".to_owned(),
+                    Err(err) => {
+                        println!("Error on .edit_rule: {}", err);
+                        format!("Error on editing rule: {}", err)
+                    }
+                };
+                zulip::web::post_message(
+                    zulip_message,
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalRemoveRule { name, author } => {
+                let zulip_message = match rule_manager.remove_rule(name.clone(), &author) {
                     Ok(removed) => {
+                        if let Err(e) = match_history.forget_rule(&name) {
+                            println!("Error in .forget_rule: {}", e);
+                        }
                         if removed {
                             "Rule removed!".to_owned()
                         } else {
@@ -356,8 +425,66 @@ pub fn handle_events(
                     zulip_url,
                 );
             }
-            Event::InternalDisableRules(pattern) => {
-                let zulip_message = match rule_manager.disable_rules(pattern) {
+            Event::InternalRemoveTag { tag, author } => {
+                let zulip_message =
+                    match rule_manager.remove_rules(RuleSelection::Tag(tag), &author) {
+                        Ok(names) => {
+                            for name in &names {
+                                if let Err(e) = match_history.forget_rule(name) {
+                                    println!("Error in .forget_rule: {}", e);
+                                }
+                            }
+                            format!("{} rules removed.", names.len())
+                        }
+                        Err(err) => {
+                            println!("Error on .remove_rules: {}", err);
+                            format!("Error on removing rules: {}", err)
+                        }
+                    };
+                zulip::web::post_message(
+                    zulip_message,
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalRuleHistory(name) => {
+                let lines = rule_manager.history().friendly(&name);
+                zulip::web::post_message(
+                    if lines.is_empty() {
+                        format!("No history found for rule `{}`.", &name)
+                    } else {
+                        format!("History of rule `{}`:\n{}", &name, lines.join("\n"))
+                    },
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalRestoreRule {
+                name,
+                revision,
+                author,
+            } => {
+                zulip::web::post_message(
+                    match rule_manager.restore(&name, revision, &author) {
+                        Ok(true) => format!("Rule `{}` restored to revision {}!", &name, revision),
+                        Ok(false) => "No such revision found.".to_owned(),
+                        Err(e) => format!("Error on restoring: {:?}", e),
+                    },
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalDisableRules { selection, author } => {
+                let zulip_message = match rule_manager.disable_rules(selection, &author) {
                     Ok(count) => format!("{} rules disabled.", count),
                     Err(err) => format!("Error on disabling rules: {}", err),
                 };
@@ -370,8 +497,8 @@ pub fn handle_events(
                     zulip_url,
                 );
             }
-            Event::InternalEnableRules(pattern) => {
-                let zulip_message = match rule_manager.enable_rules(pattern) {
+            Event::InternalEnableRules { selection, author } => {
+                let zulip_message = match rule_manager.enable_rules(selection, &author) {
                     Ok(count) => format!("{} rules enabled.", count),
                     Err(err) => format!("Error on enabling rules: {}", err),
                 };
@@ -384,8 +511,11 @@ pub fn handle_events(
                     zulip_url,
                 );
             }
-            Event::InternalListRules => zulip::web::post_message(
-                format!("Current rules: {}", rule_manager.list_names().join(", ")),
+            Event::InternalListRules(tag) => zulip::web::post_message(
+                format!(
+                    "Current rules:\n{}",
+                    rule_manager.list_names(tag.as_deref()).join("\n")
+                ),
                 zulip_bot_id,
                 zulip_bot_token,
                 zulip_command_stream,
@@ -395,8 +525,13 @@ pub fn handle_events(
             Event::InternalStreamEventReceived => latest_event_utc = Utc::now(),
             Event::InternalZulipStatusCommand => zulip::web::post_message(
                 format!(
-                    "I am alive! Latest event: (UTC) {}",
-                    latest_event_utc.format("%d/%m/%Y %T")
+                    "I am alive! Latest event: (UTC) {}{}",
+                    latest_event_utc.format("%d/%m/%Y %T"),
+                    if action_limiter.tripped() {
+                        ". Circuit breaker is tripped, destructive actions are on hold"
+                    } else {
+                        ""
+                    }
                 ),
                 zulip_bot_id,
                 zulip_bot_token,
@@ -427,8 +562,9 @@ pub fn handle_events(
             ),
             Event::InternalCheckRulesExpiry => {
                 let mut rules_to_remove = vec![];
+                let mut notices = vec![];
 
-                for rule in &mut rule_manager.rules {
+                for rule in rule_manager.rules() {
                     if let Some(expiry) = rule.expiry {
                         if expiry < Utc::now().add(Duration::days(1)) && rule.exp_notification == 0
                         {
@@ -443,7 +579,7 @@ pub fn handle_events(
                                 zulip_notify_topic,
                                 zulip_url,
                             );
-                            rule.exp_notification = 1;
+                            notices.push((rule.name.clone(), 1));
                         } else if expiry < Utc::now() && rule.exp_notification <= 1 {
                             zulip::web::post_message(
                                 format!("Notice: rule `{}` has expired", rule.name),
@@ -453,7 +589,7 @@ pub fn handle_events(
                                 zulip_notify_topic,
                                 zulip_url,
                             );
-                            rule.exp_notification = 2;
+                            notices.push((rule.name.clone(), 2));
                         }
 
                         if Utc::now() > expiry.add(Duration::days(3)) {
@@ -462,7 +598,10 @@ pub fn handle_events(
                     }
                 }
 
-                if let Err(e) = rule_manager.save() {
+                for (name, exp_notification) in notices {
+                    rule_manager.set_exp_notification(&name, exp_notification);
+                }
+                if let Err(e) = rule_manager.save_stats() {
                     zulip::web::post_message(
                         format!("Error while saving in InternalCheckRulesExpiry: {:?}", e),
                         zulip_bot_id,
@@ -474,7 +613,7 @@ pub fn handle_events(
                 }
 
                 for rule_to_remove in rules_to_remove {
-                    if let Err(e) = rule_manager.remove_rule(rule_to_remove) {
+                    if let Err(e) = rule_manager.remove_rule(rule_to_remove, "expiry") {
                         zulip::web::post_message(
                             format!("Error while automatically removing expired rule: {:?}", e),
                             zulip_bot_id,
@@ -486,9 +625,414 @@ pub fn handle_events(
                     }
                 }
             }
-            Event::InternalRenewRule { rule, new_expiry } => {
+            Event::InternalCheckStaleRules => {
+                let now = Utc::now();
+                match rule_manager.check_stale(stale_rule_days, stale_rule_grace_days, now) {
+                    Ok(disabled) if !disabled.is_empty() => zulip::web::post_message(
+                        format!(
+                            "Disabled stale rules (no matches in the last {} days): {}",
+                            stale_rule_days,
+                            disabled.join(", ")
+                        ),
+                        zulip_bot_id,
+                        zulip_bot_token,
+                        zulip_notify_stream,
+                        zulip_notify_topic,
+                        zulip_url,
+                    ),
+                    Ok(_) => {}
+                    Err(e) => println!("Error in .check_stale: {}", e),
+                }
+
+                // The digest goes out on Mondays, once.
+                let stale = rule_manager.stale_rules();
+                if now.weekday() == Weekday::Mon
+                    && latest_stale_digest != Some(now.date_naive())
+                    && !stale.is_empty()
+                {
+                    latest_stale_digest = Some(now.date_naive());
+                    if let Err(e) = fs::write(stale_digest_path, now.date_naive().to_string()) {
+                        println!("Error writing {}: {}", stale_digest_path, e);
+                    }
+                    zulip::web::post_message(
+                        format!(
+                            "**Stale rules** (no matches in the last {} days):\n{}",
+                            stale_rule_days,
+                            stale
+                                .iter()
+                                .map(|r| format!(
+                                    "- `{}`: created {}, {} matches, latest match {}{}",
+                                    r.name,
+                                    r.creation_date.format("%Y-%m-%d"),
+                                    r.match_count,
+                                    r.latest_match_date
+                                        .map(|d| d.format("%Y-%m-%d").to_string())
+                                        .unwrap_or("never".to_owned()),
+                                    match (r.stale_since, stale_rule_grace_days) {
+                                        (Some(since), Some(grace)) => format!(
+                                            ", will be disabled on {}",
+                                            (since + Duration::days(grace)).format("%Y-%m-%d")
+                                        ),
+                                        _ => "".to_owned(),
+                                    }
+                                ))
+                                .collect::<Vec<String>>()
+                                .join("\n")
+                        ),
+                        zulip_bot_id,
+                        zulip_bot_token,
+                        zulip_notify_stream,
+                        zulip_notify_topic,
+                        zulip_url,
+                    );
+                }
+            }
+            Event::InternalCheckRulesFile if !rule_manager.modified_externally() => {}
+            Event::InternalCheckRulesFile | Event::InternalReloadRules => {
+                zulip::web::post_message(
+                    match rule_manager.reload(&lua_state) {
+                        Ok(report) => report.friendly(),
+                        Err(e) => {
+                            format!("**Rules not reloaded**, keeping the current ones: {}", e)
+                        }
+                    },
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalExportRules(selection) => {
+                let names = match selection {
+                    Some(ref selection) => rule_manager.select(selection),
+                    None => Ok(rule_manager
+                        .rules()
+                        .iter()
+                        .map(|r| r.name.clone())
+                        .collect()),
+                };
+                let bundle = names.and_then(|names| {
+                    let rules: Vec<&Rule> = rule_manager
+                        .rules()
+                        .iter()
+                        .filter(|r| names.contains(&r.name))
+                        .collect();
+                    let bundle = RuleBundle::new(&rules)?;
+                    Ok((rules.len(), serde_json::to_string_pretty(&bundle)?))
+                });
+                match bundle {
+                    Ok((count, json)) => zulip::web::upload_file(
+                        format!("Rule bundle ({} rules):", count),
+                        format!("rules-{}.json", Utc::now().format("%Y%m%d-%H%M%S")),
+                        json.into_bytes(),
+                        zulip_bot_id,
+                        zulip_bot_token,
+                        zulip_command_stream,
+                        zulip_command_topic,
+                        zulip_url,
+                    ),
+                    Err(e) => zulip::web::post_message(
+                        format!("Error on exporting rules: {}", e),
+                        zulip_bot_id,
+                        zulip_bot_token,
+                        zulip_command_stream,
+                        zulip_command_topic,
+                        zulip_url,
+                    ),
+                }
+            }
+            Event::InternalImportRules {
+                rules,
+                mode,
+                author,
+            } => zulip::web::post_message(
+                match rule_manager.import(rules, mode, &author, &lua_state) {
+                    Ok(report) => report.friendly(),
+                    Err(e) => format!("**Rules not imported**: {}", e),
+                },
+                zulip_bot_id,
+                zulip_bot_token,
+                zulip_command_stream,
+                zulip_command_topic,
+                zulip_url,
+            ),
+            Event::InternalRunDueActions if action_limiter.tripped() => {}
+            Event::InternalRunDueActions => match action_queue.take_due(Utc::now()) {
+                Ok(due) => {
+                    for group in queue::by_match(due) {
+                        let (rule, username) = (group[0].rule.clone(), group[0].username.clone());
+                        modaction::execute_in_order(
+                            group.into_iter().map(|a| (a.action, a.payload)).collect(),
+                            &username,
+                            rule,
+                            token,
+                            webhook_secret,
+                            tx.clone(),
+                        );
+                    }
+                }
+                Err(e) => println!("Error in .take_due: {}", e),
+            },
+            Event::InternalListQueue => {
+                let zulip_message = if action_queue.actions.is_empty() {
+                    "The action queue is empty.".to_owned()
+                } else {
+                    let mut queued = action_queue.actions.clone();
+                    queued.sort_by_key(|a| a.due);
+                    format!(
+                        "{} queued actions:\n{}",
+                        queued.len(),
+                        queued
+                            .iter()
+                            .take(50)
+                            .map(|a| format!(
+                                "* `{:?}` on [{}](https://lichess.org/@/{}?mod) by rule `{}`, due (UTC) {}",
+                                a.action,
+                                &a.username.0,
+                                &a.username.0,
+                                &a.rule,
+                                a.due.format("%d/%m/%Y %T")
+                            ))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    )
+                };
+                zulip::web::post_message(
+                    zulip_message,
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalCancelQueued(username) => {
+                zulip::web::post_message(
+                    match action_queue.cancel_user(&username) {
+                        Ok(count) => format!("{} queued actions cancelled.", count),
+                        Err(e) => format!("Error on cancelling queued actions: {:?}", e),
+                    },
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalResetBreaker => {
+                action_limiter.reset();
+                let reply = match action_limiter.save() {
+                    Ok(()) => "Circuit breaker reset, destructive actions resumed.".to_owned(),
+                    Err(e) => format!(
+                        "Circuit breaker reset, destructive actions resumed. \
+                         Could not save the reset, it will be tripped again after a restart: {}",
+                        e
+                    ),
+                };
+                zulip::web::post_message(
+                    reply,
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalActionOutcome {
+                rule,
+                username,
+                action,
+                revert,
+                outcome,
+            } => {
+                if let Err(e) = audit_log.record(&AuditEntry {
+                    date: Utc::now(),
+                    rule: rule.clone(),
+                    username: username.clone(),
+                    record: AuditRecord::Action {
+                        action: action.clone(),
+                        revert,
+                        outcome: outcome.clone(),
+                    },
+                }) {
+                    println!("Error in audit .record: {}", e);
+                }
+
+                match outcome {
+                    ActionOutcome::Done { .. } => {
+                        if let Err(e) = action_log.record(ActionLogEntry {
+                            date: Utc::now(),
+                            rule,
+                            username,
+                            action,
+                            reverted: revert,
+                        }) {
+                            println!("Error in .record: {}", e);
+                        }
+                    }
+                    ActionOutcome::Failed { reason, .. } => {
+                        let err_msg = format!(
+                        "{} `{:?}` of rule `{}` on [{}](https://lichess.org/@/{}?mod) failed: {}",
+                        if revert { "Reverting action" } else { "Action" },
+                        action,
+                        &rule,
+                        &username.0,
+                        &username.0,
+                        reason
+                    );
+                        println!("{}", err_msg.clone());
+                        zulip::web::post_message(
+                            err_msg,
+                            zulip_bot_id,
+                            zulip_bot_token,
+                            zulip_notify_stream,
+                            zulip_notify_topic,
+                            zulip_url,
+                        );
+                    }
+                }
+            }
+            Event::InternalAuditSearch(query) => zulip::web::post_message(
+                match audit_log.search(&query, 20) {
+                    Ok(entries) if entries.is_empty() => {
+                        format!("No audit entries found for {}.", query.friendly())
+                    }
+                    Ok(entries) => format!(
+                        "Latest {} audit entries for {}:\n{}",
+                        entries.len(),
+                        query.friendly(),
+                        entries
+                            .iter()
+                            .map(|e| format!("* {}", e.friendly()))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    ),
+                    Err(e) => format!("Error on searching the audit log: {:?}", e),
+                },
+                zulip_bot_id,
+                zulip_bot_token,
+                zulip_command_stream,
+                zulip_command_topic,
+                zulip_url,
+            ),
+            Event::InternalAuditExport(query) => match audit_log.search(&query, usize::MAX) {
+                Ok(entries) => zulip::web::upload_file(
+                    format!(
+                        "Audit log export for {} ({} entries):",
+                        query.friendly(),
+                        entries.len()
+                    ),
+                    format!("audit-{}.csv", Utc::now().format("%Y%m%d-%H%M%S")),
+                    audit::to_csv(&entries).into_bytes(),
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                ),
+                Err(e) => zulip::web::post_message(
+                    format!("Error on exporting the audit log: {:?}", e),
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                ),
+            },
+            Event::InternalRevertRule {
+                rule,
+                since,
+                confirmed,
+            } => {
+                let applied = action_log.applied_by_rule(&rule, since);
+                let (revertible, irreversible): (Vec<&ActionLogEntry>, Vec<&ActionLogEntry>) =
+                    applied
+                        .iter()
+                        .partition(|e| e.action.inverse_endpoint(&e.username).is_some());
+                let mut users: Vec<&str> =
+                    revertible.iter().map(|e| e.username.0.as_str()).collect();
+                users.sort_unstable();
+                users.dedup();
+
+                let zulip_message = if revertible.is_empty() {
+                    format!(
+                        "No revertible actions found for rule `{}`{}.",
+                        &rule,
+                        if irreversible.is_empty() {
+                            "".to_owned()
+                        } else {
+                            format!(" ({} actions cannot be reverted)", irreversible.len())
+                        }
+                    )
+                } else if !confirmed {
+                    format!(
+                        "Reverting rule `{}` would undo {} actions on {} users: {}.{} \
+                         Repeat the command with `confirm` at the end to proceed.",
+                        &rule,
+                        revertible.len(),
+                        users.len(),
+                        users
+                            .iter()
+                            .take(30)
+                            .map(|u| format!("[{}](https://lichess.org/@/{}?mod)", u, u))
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                        if irreversible.is_empty() {
+                            "".to_owned()
+                        } else {
+                            format!(
+                                " {} actions cannot be reverted ({}).",
+                                irreversible.len(),
+                                irreversible
+                                    .iter()
+                                    .map(|e| format!("`{:?}` on {}", e.action, &e.username.0))
+                                    .collect::<Vec<String>>()
+                                    .join(", ")
+                            )
+                        }
+                    )
+                } else {
+                    for entry in &revertible {
+                        modaction::revert(
+                            &entry.action,
+                            &entry.username,
+                            rule.clone(),
+                            token,
+                            tx.clone(),
+                        );
+                    }
+                    let cancelled = action_queue.cancel_rule(&rule).unwrap_or_else(|e| {
+                        println!("Error in .cancel_rule: {}", e);
+                        0
+                    });
+                    format!(
+                        "Reverting {} actions on {} users for rule `{}`. \
+                         {} queued actions were cancelled, {} actions cannot be reverted. \
+                         Failures will be reported in the notify topic.",
+                        revertible.len(),
+                        users.len(),
+                        &rule,
+                        cancelled,
+                        irreversible.len()
+                    )
+                };
+                zulip::web::post_message(
+                    zulip_message,
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
+            Event::InternalRenewRule {
+                rule,
+                new_expiry,
+                author,
+            } => {
                 zulip::web::post_message(
-                    match rule_manager.renew(rule, new_expiry) {
+                    match rule_manager.renew(rule, new_expiry, &author) {
                         Ok(_) => "Rule renewed!".to_owned(),
                         Err(e) => format!("Error on renewing: {:?}", e),
                     },
@@ -499,6 +1043,231 @@ pub fn handle_events(
                     zulip_url,
                 );
             }
+            Event::InternalRenewTag {
+                tag,
+                new_expiry,
+                author,
+            } => {
+                zulip::web::post_message(
+                    match rule_manager.renew_rules(RuleSelection::Tag(tag), new_expiry, &author) {
+                        Ok(renewed) => format!("{} rules renewed.", renewed),
+                        Err(e) => format!("Error on renewing: {:?}", e),
+                    },
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_command_stream,
+                    zulip_command_topic,
+                    zulip_url,
+                );
+            }
         }
     }
 }
+
+/// The Lichess and Zulip settings that matched rules act with.
+struct DispatchSettings {
+    token: &'static str,
+    webhook_secret: &'static str,
+    zulip_bot_id: &'static str,
+    zulip_bot_token: &'static str,
+    zulip_notify_stream: &'static str,
+    zulip_notify_topic: &'static str,
+    zulip_log_stream: &'static str,
+    zulip_log_topic: &'static str,
+    zulip_url: &'static str,
+}
+
+/// What [`dispatch`] records matches in and takes actions with.
+struct Dispatch<'a> {
+    audit_log: &'a AuditLog,
+    action_limiter: &'a mut ActionLimiter,
+    action_queue: &'a mut ActionQueue,
+    recently_notified: &'a mut VecDeque<String>,
+    tx: &'a Sender<Event>,
+    settings: &'a DispatchSettings,
+}
+
+/// Takes the planned actions of a real match: records it in the audit log, applies the
+/// hourly caps, then runs, queues or posts each action. Returns the rule's cap if the match
+/// exceeded it.
+fn dispatch(
+    planned: &PlannedMatch,
+    user: &User,
+    delay_roll: f64,
+    context: Dispatch,
+) -> Option<usize> {
+    let Dispatch {
+        audit_log,
+        action_limiter,
+        action_queue,
+        recently_notified,
+        tx,
+        settings,
+    } = context;
+    let DispatchSettings {
+        token,
+        webhook_secret,
+        zulip_bot_id,
+        zulip_bot_token,
+        zulip_notify_stream,
+        zulip_notify_topic,
+        zulip_log_stream,
+        zulip_log_topic,
+        zulip_url,
+    } = *settings;
+    let rule = planned.rule;
+    let actions = &planned.actions;
+    let user_id = user.username.0.to_lowercase();
+    let mut over_cap = None;
+
+    if let Err(e) = audit_log.record(&AuditEntry {
+        date: Utc::now(),
+        rule: rule.name.clone(),
+        username: user.username.clone(),
+        record: AuditRecord::Match {
+            criterion: rule.criterion.clone(),
+            actions: actions.clone(),
+            user: Box::new(user.clone()),
+        },
+    }) {
+        println!("Error in audit .record: {}", e);
+    }
+
+    let destructive_count = actions.iter().filter(|a| a.is_destructive()).count();
+    let allow_destructive = destructive_count == 0
+        || match action_limiter.check(&rule.name, rule.hourly_cap, destructive_count, Utc::now()) {
+            LimitDecision::Allowed => true,
+            LimitDecision::RuleCapExceeded(cap) => {
+                over_cap = Some(cap);
+                false
+            }
+            LimitDecision::GlobalCapExceeded(cap) => {
+                zulip::web::post_message(
+                    format!(
+                        "**Circuit breaker tripped**: more than {} destructive actions in the last hour \
+                         (last match: rule `{}` on [{}](https://lichess.org/@/{}?mod)). \
+                         All destructive actions, including queued ones, are on hold. \
+                         Review the recent matches and resume with `signup breaker reset`.",
+                        cap, &rule.name, &user.username.0, &user_id
+                    ),
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_notify_stream,
+                    zulip_notify_topic,
+                    zulip_url,
+                );
+                false
+            }
+            LimitDecision::Tripped => false,
+        };
+    if destructive_count > 0 {
+        if let Err(e) = action_limiter.save() {
+            println!("Error in limiter .save: {}", e);
+        }
+    }
+
+    let mut immediate = vec![];
+    for action in actions {
+        if action.is_destructive() && !allow_destructive {
+            if let Err(e) = audit_log.record(&AuditEntry {
+                date: Utc::now(),
+                rule: rule.name.clone(),
+                username: user.username.clone(),
+                record: AuditRecord::Action {
+                    action: action.clone(),
+                    revert: false,
+                    outcome: ActionOutcome::Failed {
+                        status: None,
+                        reason: "held back by the hourly caps".to_owned(),
+                    },
+                },
+            }) {
+                println!("Error in audit .record: {}", e);
+            }
+            continue;
+        }
+        if action.is_local() {
+            if action.eq(&Action::NotifyZulip) && !recently_notified.contains(&user_id) {
+                zulip::web::post_message(
+                    format!(
+                        "Rule {} match: [{}](https://lichess.org/@/{}?mod)",
+                        &rule.name, &user.username.0, &user_id
+                    ),
+                    zulip_bot_id,
+                    zulip_bot_token,
+                    zulip_notify_stream,
+                    zulip_notify_topic,
+                    zulip_url,
+                );
+
+                recently_notified.push_back(user_id.clone());
+                if recently_notified.len() > 2000 {
+                    recently_notified.pop_front();
+                }
+            }
+        } else {
+            match rule.action_delay(action) {
+                Some(range) => {
+                    let additional = if action.eq(&Action::Close) {
+                        time::Duration::from_millis(1500)
+                    } else {
+                        time::Duration::from_millis(0)
+                    };
+                    let delay = range.pick(delay_roll) + additional;
+                    let due = Utc::now() + Duration::from_std(delay).unwrap_or(Duration::zero());
+                    if let Err(e) = action_queue.schedule(
+                        rule.name.clone(),
+                        user.username.clone(),
+                        action.clone(),
+                        action.webhook_payload(rule, user),
+                        due,
+                    ) {
+                        println!("Error in .schedule: {}", e);
+                    }
+                }
+                None => immediate.push((action.clone(), action.webhook_payload(rule, user))),
+            }
+        }
+    }
+    immediate.sort_by_key(|(action, _)| action.eq(&Action::Close));
+    modaction::execute_in_order(
+        immediate,
+        &user.username,
+        rule.name.clone(),
+        token,
+        webhook_secret,
+        tx.clone(),
+    );
+
+    if actions.len() > 1 || !actions.get(0).eq(&Some(&Action::NotifyZulip)) {
+        zulip::web::post_message(
+            format!(
+                "Rule {} match: \
+                 {} on [{}](https://lichess.org/@/{}?mod). \
+                 {} previous matches. \
+                 Recent matches: {}",
+                &rule.name,
+                &rule.criterion.friendly(),
+                &user.username.0,
+                &user.username.0,
+                &rule.match_count,
+                if rule.most_recent_caught.len() == 0 {
+                    "None".to_string()
+                } else {
+                    rule.most_recent_caught
+                        .iter()
+                        .map(|u| format!("[{}](https://lichess.org/@/{}?mod)", &u, &u))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                }
+            ),
+            zulip_bot_id,
+            zulip_bot_token,
+            zulip_log_stream,
+            zulip_log_topic,
+            zulip_url,
+        );
+    }
+
+    over_cap
+}
@@ -0,0 +1,39 @@
+//! The rule semantics of the Lichess signup watcher, for tools that want to judge signups
+//! the way the bot does.
+//!
+//! A signup is a [`User`]. [`Enricher`] fills in its [`GeoipInfo`] and [`DeviceInfo`], then
+//! [`RuleEngine`] evaluates a set of [`Rule`]s on it, in priority order, and returns the
+//! [`Action`]s they call for without taking them. Rules whose [`Criterion`] is a Lua snippet
+//! need a Lua state from [`lua::new_lua`].
+//!
+//! ```no_run
+//! use lichess_event_stream::{lua, Enricher, Rule, RuleEngine, User};
+//!
+//! let rules: Vec<Rule> = serde_json::from_str(&std::fs::read_to_string("rules/rules.json")?)?;
+//! let mut user = User::from_json(r#"{"username": "x", "email": "x@y.z", "ip": "1.2.3.4"}"#)?;
+//! Enricher::open("GeoLite2-City.mmdb", "regexes.yaml")?.enrich(&mut user)?;
+//! let lua_state = lua::new_lua();
+//! let engine = RuleEngine::new(&lua_state);
+//! let evaluation = engine.evaluate(&rules, &user, chrono::Utc::now(), |_, _| 1);
+//! for planned in evaluation.matches() {
+//!     println!("{}: {:?}", planned.rule.name, planned.actions);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod lua;
+
+// The bot's own modules, shared with its binary. They are not part of the API, which is
+// re-exported below.
+#[doc(hidden)]
+pub mod event;
+#[doc(hidden)]
+pub mod signup;
+
+pub use event::{
+    DeviceInfo, Email, Enricher, FingerPrint, GeoipInfo, Ip, User, UserAgent, Username,
+};
+pub use signup::engine::{Evaluation, PlannedMatch, RuleEngine, RuleOutcome};
+pub use signup::escalation::{Escalation, EscalationKey};
+pub use signup::rules::{Action, ActionDelay, Criterion, DelayRange, Rule};
+pub use signup::schedule::{HourRange, Schedule};
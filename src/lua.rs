@@ -69,6 +69,8 @@ impl UserData for User {
     }
 }
 
+/// A Lua state for constraints snippets, with the `regex(text, pattern)` and
+/// `isInIpRange(ip, min, max)` helpers.
 pub fn new_lua() -> Lua {
     let l = Lua::new();
     l.context(|lua_ctx| {
@@ -106,6 +108,8 @@ pub fn new_lua() -> Lua {
     l
 }
 
+/// Evaluates a constraints snippet, an expression such as `user:country() == "XX"`, on the
+/// user.
 pub fn call_constraints_function(rule: &str, user: User, l: &Lua) -> Result<bool, rlua::Error> {
     let mut v: bool = false;
     l.context(|lua_ctx| {
@@ -117,3 +121,13 @@ pub fn call_constraints_function(rule: &str, user: User, l: &Lua) -> Result<bool
     })?;
     Ok(v)
 }
+
+/// Compiles a constraints snippet without running it, to catch syntax errors early.
+pub fn check_constraints_function(rule: &str, l: &Lua) -> Result<(), rlua::Error> {
+    l.context(|lua_ctx| {
+        lua_ctx
+            .load(&("return function(user) return ".to_owned() + rule + " end"))
+            .into_function()
+            .map(|_| ())
+    })
+}
@@ -1,16 +1,21 @@
+mod cli;
 mod conf;
-mod event;
 mod eventhandler;
 mod eventstream;
-mod lua;
-mod signup;
+mod modaction;
 mod status;
 mod zulip;
 
 use futures::future;
+use lichess_event_stream::{event, lua, signup};
 use std::sync::mpsc::channel;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     tokio::run(future::lazy(move || {
         let (tx, rx) = channel::<event::Event>();
         let (status_tx, status_rx) = channel::<status::StatusPing>();
@@ -31,11 +36,37 @@ fn main() {
         status::status_loop(status_rx, tx.clone(), conf::TOKEN, status_tx.clone());
         status::periodically_ensure_alive_connection(status_tx.clone());
         signup::rules::expiry_loop(tx.clone());
+        signup::rules::stale_loop(tx.clone());
+        signup::rules::reload_loop(tx.clone());
+        signup::queue::queue_loop(tx.clone());
+
+        let storage = signup::storage::open(
+            conf::STORAGE_BACKEND,
+            conf::RULES_PATH,
+            conf::RULES_BACKUPS,
+            conf::AUDIT_LOG_PATH,
+            conf::RULES_TOML_PATH,
+            conf::RULES_STATS_PATH,
+            conf::SQLITE_PATH,
+        )
+        .expect("could not open storage");
 
         eventhandler::handle_events(
             rx,
+            tx.clone(),
             conf::TOKEN,
-            conf::RULES_PATH,
+            conf::WEBHOOK_SECRET,
+            storage,
+            conf::RULE_HISTORY_PATH,
+            conf::ACTION_QUEUE_PATH,
+            conf::ACTION_LIMITER_PATH,
+            conf::ACTION_LOG_PATH,
+            conf::ESCALATION_HISTORY_PATH,
+            conf::GLOBAL_ACTIONS_PER_HOUR,
+            conf::RULE_ACTIONS_PER_HOUR,
+            conf::STALE_RULE_DAYS,
+            conf::STALE_RULE_GRACE_DAYS,
+            conf::STALE_DIGEST_PATH,
             conf::GEOIP_DB_PATH,
             conf::UAP_REGEXES_PATH,
             conf::ZULIP_BOT_ID,
@@ -0,0 +1,269 @@
+use crate::event::{Event, Username};
+use crate::signup::rules::{Action, ActionOutcome};
+
+use futures::future::{self, Loop};
+use futures::stream::{self, Stream};
+use hmac::{Hmac, Mac};
+use hyper::header::HeaderValue;
+use hyper::rt::Future;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use sha2::Sha256;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use tokio;
+use tokio::timer::Delay;
+
+const MAX_ATTEMPTS: u32 = 5;
+
+enum Attempt {
+    Finished(ActionOutcome),
+    Retry { wait: Duration, reason: String },
+}
+
+/// An HTTP request built once and sent as many times as needed.
+struct OutgoingRequest {
+    url: String,
+    body: String,
+    headers: Vec<(hyper::header::HeaderName, String)>,
+}
+
+impl OutgoingRequest {
+    fn lichess(endpoint: String, form: Option<String>, token: &'static str) -> Self {
+        let mut headers = vec![(hyper::header::AUTHORIZATION, "Bearer ".to_owned() + token)];
+        if form.is_some() {
+            headers.push((
+                hyper::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded".to_owned(),
+            ));
+        }
+        OutgoingRequest {
+            url: endpoint,
+            body: form.unwrap_or_default(),
+            headers,
+        }
+    }
+
+    fn webhook(url: String, payload: String, secret: &'static str) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(payload.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        OutgoingRequest {
+            url,
+            body: payload,
+            headers: vec![
+                (hyper::header::CONTENT_TYPE, "application/json".to_owned()),
+                (
+                    hyper::header::HeaderName::from_static("x-signature-256"),
+                    format!("sha256={}", signature),
+                ),
+            ],
+        }
+    }
+
+    fn build(&self) -> Result<Request<Body>, String> {
+        let mut req = Request::new(Body::from(self.body.clone()));
+        *req.uri_mut() = self
+            .url
+            .parse()
+            .map_err(|e| format!("invalid URL `{}`: {}", self.url, e))?;
+        *req.method_mut() = Method::POST;
+        for (name, value) in &self.headers {
+            req.headers_mut().insert(
+                name.clone(),
+                HeaderValue::from_str(value)
+                    .map_err(|e| format!("invalid {} header: {}", name, e))?,
+            );
+        }
+        Ok(req)
+    }
+}
+
+/// Takes the actions of one match on `username` one after another, each once the previous
+/// one is done, retries included, so that e.g. marks land before a close. Outcomes are
+/// reported back to the event handler. Webhook actions need the payload that was prepared
+/// when the rule matched.
+pub fn execute_in_order(
+    actions: Vec<(Action, Option<String>)>,
+    username: &Username,
+    rule: String,
+    token: &'static str,
+    webhook_secret: &'static str,
+    tx: Sender<Event>,
+) {
+    let requests: Vec<(OutgoingRequest, Action)> = actions
+        .into_iter()
+        .filter_map(|(action, payload)| {
+            let request = match &action {
+                Action::Webhook { url, .. } => payload
+                    .map(|payload| OutgoingRequest::webhook(url.clone(), payload, webhook_secret)),
+                _ => action.api_endpoint(username).map(|endpoint| {
+                    OutgoingRequest::lichess(endpoint, action.api_form(&rule, username), token)
+                }),
+            };
+            request.map(|request| (request, action))
+        })
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+    let username = username.clone();
+    tokio::spawn(
+        stream::iter_ok(requests).for_each(move |(request, action)| {
+            attempt(
+                request,
+                action,
+                username.clone(),
+                rule.clone(),
+                false,
+                tx.clone(),
+            )
+        }),
+    );
+}
+
+/// Undoes `action` on `username` and reports the outcome back to the event handler.
+pub fn revert(
+    action: &Action,
+    username: &Username,
+    rule: String,
+    token: &'static str,
+    tx: Sender<Event>,
+) {
+    if let Some(endpoint) = action.inverse_endpoint(username) {
+        tokio::spawn(attempt(
+            OutgoingRequest::lichess(endpoint, None, token),
+            action.clone(),
+            username.clone(),
+            rule,
+            true,
+            tx,
+        ));
+    }
+}
+
+/// Sends the request and reports the outcome. Rate limits, server errors and connection
+/// errors are retried with backoff.
+fn attempt(
+    request: OutgoingRequest,
+    action: Action,
+    username: Username,
+    rule: String,
+    revert: bool,
+    tx: Sender<Event>,
+) -> impl Future<Item = (), Error = ()> + Send {
+    future::lazy(move || {
+        let https = HttpsConnector::new(1);
+        let client = Client::builder().build::<_, Body>(https);
+        let lichess = request.url.starts_with("https://lichess.org/");
+
+        future::loop_fn(1, move |attempt| {
+            let req = match request.build() {
+                Ok(req) => req,
+                Err(reason) => {
+                    let failed: Box<
+                        dyn Future<Item = Loop<ActionOutcome, u32>, Error = ()> + Send,
+                    > = Box::new(future::ok(Loop::Break(ActionOutcome::Failed {
+                        status: None,
+                        reason,
+                    })));
+                    return failed;
+                }
+            };
+            Box::new(client.request(req).then(move |result| {
+                let next: Box<dyn Future<Item = Loop<ActionOutcome, u32>, Error = ()> + Send> =
+                    match check_response(result, attempt, lichess) {
+                        Attempt::Finished(outcome) => Box::new(future::ok(Loop::Break(outcome))),
+                        Attempt::Retry { reason, .. } if attempt >= MAX_ATTEMPTS => {
+                            Box::new(future::ok(Loop::Break(ActionOutcome::Failed {
+                                status: None,
+                                reason: format!("gave up after {} attempts: {}", attempt, reason),
+                            })))
+                        }
+                        Attempt::Retry { wait, reason } => {
+                            println!(
+                                "Mod action attempt {} failed ({}), retrying in {}s.",
+                                attempt,
+                                reason,
+                                wait.as_secs()
+                            );
+                            Box::new(
+                                Delay::new(Instant::now() + wait)
+                                    .map_err(|e| println!("Err in mod action retry: {}", e))
+                                    .map(move |_| Loop::Continue(attempt + 1)),
+                            )
+                        }
+                    };
+                next
+            }))
+        })
+        .map(move |outcome| {
+            if let ActionOutcome::Done { status } = outcome {
+                println!("Action: {}.", status);
+            }
+            tx.send(Event::InternalActionOutcome {
+                rule,
+                username,
+                action,
+                revert,
+                outcome,
+            })
+            .unwrap();
+        })
+    })
+}
+
+fn check_response(
+    result: Result<hyper::Response<Body>, hyper::Error>,
+    attempt: u32,
+    lichess: bool,
+) -> Attempt {
+    let backoff = Duration::from_secs(2u64.pow(attempt));
+    match result {
+        Err(err) => Attempt::Retry {
+            wait: backoff,
+            reason: format!("connection error: {}", err),
+        },
+        Ok(res) => {
+            let status = res.status();
+            if status.is_success() {
+                Attempt::Finished(ActionOutcome::Done {
+                    status: status.as_u16(),
+                })
+            } else if status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = res
+                    .headers()
+                    .get(hyper::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs);
+                Attempt::Retry {
+                    wait: retry_after.unwrap_or(backoff),
+                    reason: format!("HTTP {}", status),
+                }
+            } else if status.is_server_error() {
+                Attempt::Retry {
+                    wait: backoff,
+                    reason: format!("HTTP {}", status),
+                }
+            } else {
+                Attempt::Finished(ActionOutcome::Failed {
+                    status: Some(status.as_u16()),
+                    reason: if lichess
+                        && (status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN)
+                    {
+                        format!("HTTP {} (is the Lichess API token still valid?)", status)
+                    } else {
+                        format!("HTTP {}", status)
+                    },
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::event::Username;
+use crate::signup::rules::Action;
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+};
+
+/// Append-only log of the mod actions that were successfully taken or reverted.
+pub struct ActionLog {
+    pub entries: Vec<ActionLogEntry>,
+    log_path: String,
+}
+
+impl ActionLog {
+    pub fn new(log_path: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut entries = vec![];
+        match File::open(&log_path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        entries.push(serde_json::from_str(&line)?);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(ActionLog { entries, log_path })
+    }
+
+    pub fn record(&mut self, entry: ActionLogEntry) -> Result<(), Box<dyn std::error::Error>> {
+        let mut f = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.log_path)?;
+        writeln!(f, "{}", serde_json::to_string(&entry)?)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// The actions taken by `rule` since `since` that have not been reverted yet.
+    pub fn applied_by_rule(&self, rule: &str, since: Option<DateTime<Utc>>) -> Vec<ActionLogEntry> {
+        let mut applied: Vec<ActionLogEntry> = vec![];
+        for entry in self.entries.iter().filter(|e| e.rule.eq(rule)) {
+            if entry.reverted {
+                applied.retain(|a| !(a.username.eq(&entry.username) && a.action.eq(&entry.action)));
+            } else if since.is_none_or(|since| entry.date >= since)
+                && !applied
+                    .iter()
+                    .any(|a| a.username.eq(&entry.username) && a.action.eq(&entry.action))
+            {
+                applied.push(entry.clone());
+            }
+        }
+        applied
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ActionLogEntry {
+    #[serde(with = "ts_milliseconds")]
+    pub date: DateTime<Utc>,
+    pub rule: String,
+    pub username: Username,
+    pub action: Action,
+    #[serde(default = "default_reverted")]
+    pub reverted: bool,
+}
+
+fn default_reverted() -> bool {
+    false
+}
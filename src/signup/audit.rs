@@ -0,0 +1,173 @@
+use crate::event::{User, Username};
+use crate::signup::rules::{Action, ActionOutcome, Criterion};
+use crate::signup::storage::Storage;
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+
+/// Append-only record of every rule match and every mod action taken.
+pub struct AuditLog {
+    storage: Rc<dyn Storage>,
+}
+
+impl AuditLog {
+    pub fn new(storage: Rc<dyn Storage>) -> Self {
+        AuditLog { storage }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>> {
+        self.storage.record_audit(entry)
+    }
+
+    /// The latest `limit` entries matching `query`, oldest first.
+    pub fn search(
+        &self,
+        query: &AuditQuery,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+        self.storage.search_audit(query, limit)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    #[serde(with = "ts_milliseconds")]
+    pub date: DateTime<Utc>,
+    pub rule: String,
+    pub username: Username,
+    pub record: AuditRecord,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum AuditRecord {
+    Match {
+        criterion: Criterion,
+        actions: Vec<Action>,
+        user: Box<User>,
+    },
+    Action {
+        action: Action,
+        revert: bool,
+        outcome: ActionOutcome,
+    },
+}
+
+impl AuditEntry {
+    pub fn friendly(&self) -> String {
+        let when = self.date.format("%d/%m/%Y %T");
+        match &self.record {
+            AuditRecord::Match { criterion, .. } => format!(
+                "{}: rule `{}` matched [{}](https://lichess.org/@/{}?mod) ({})",
+                when,
+                &self.rule,
+                &self.username.0,
+                &self.username.0,
+                criterion.friendly()
+            ),
+            AuditRecord::Action {
+                action,
+                revert,
+                outcome,
+            } => format!(
+                "{}: {}`{:?}` on [{}](https://lichess.org/@/{}?mod) by rule `{}`: {}",
+                when,
+                if *revert { "reverted " } else { "" },
+                action,
+                &self.username.0,
+                &self.username.0,
+                &self.rule,
+                friendly_outcome(outcome)
+            ),
+        }
+    }
+
+    fn csv_row(&self) -> Vec<String> {
+        let (kind, detail, result) = match &self.record {
+            AuditRecord::Match {
+                criterion,
+                actions,
+                user,
+            } => (
+                "match",
+                format!(
+                    "{} => {:?}; {}",
+                    criterion.friendly(),
+                    actions,
+                    serde_json::to_string(user).unwrap_or_default()
+                ),
+                "".to_owned(),
+            ),
+            AuditRecord::Action {
+                action,
+                revert,
+                outcome,
+            } => (
+                if *revert { "revert" } else { "action" },
+                format!("{:?}", action),
+                friendly_outcome(outcome),
+            ),
+        };
+        vec![
+            self.date.to_rfc3339(),
+            self.rule.clone(),
+            self.username.0.clone(),
+            kind.to_owned(),
+            detail,
+            result,
+        ]
+    }
+}
+
+fn friendly_outcome(outcome: &ActionOutcome) -> String {
+    match outcome {
+        ActionOutcome::Done { status } => format!("done (HTTP {})", status),
+        ActionOutcome::Failed {
+            status: Some(status),
+            reason,
+        } => format!("failed (HTTP {}): {}", status, reason),
+        ActionOutcome::Failed {
+            status: None,
+            reason,
+        } => format!("failed: {}", reason),
+    }
+}
+
+pub fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("date,rule,username,kind,detail,result\n");
+    for entry in entries {
+        let row: Vec<String> = entry
+            .csv_row()
+            .iter()
+            .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+            .collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+#[derive(Deserialize, Clone)]
+pub enum AuditQuery {
+    All,
+    User(String),
+    Rule(String),
+}
+
+impl AuditQuery {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        match self {
+            AuditQuery::All => true,
+            AuditQuery::User(name) => entry.username.0.eq_ignore_ascii_case(name),
+            AuditQuery::Rule(name) => entry.rule.eq(name),
+        }
+    }
+
+    pub fn friendly(&self) -> String {
+        match self {
+            AuditQuery::All => "all entries".to_owned(),
+            AuditQuery::User(name) => format!("user `{}`", name),
+            AuditQuery::Rule(name) => format!("rule `{}`", name),
+        }
+    }
+}
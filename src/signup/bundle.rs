@@ -0,0 +1,140 @@
+use crate::signup::rules::Rule;
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The fields of a `Rule` that are runtime stats rather than part of its definition.
+pub const STATS_FIELDS: [&str; 7] = [
+    "match_count",
+    "most_recent_caught",
+    "latest_match_date",
+    "exp_notification",
+    "stale_since",
+    "revision",
+    "creation_date",
+];
+
+/// A portable set of rule definitions, without stats, to move rules between bots or keep
+/// them in version control.
+#[derive(Serialize, Deserialize)]
+pub struct RuleBundle {
+    #[serde(with = "ts_milliseconds")]
+    pub exported: DateTime<Utc>,
+    pub rules: Vec<Value>,
+}
+
+impl RuleBundle {
+    pub fn new(rules: &[&Rule]) -> Result<Self, serde_json::Error> {
+        Ok(RuleBundle {
+            exported: Utc::now(),
+            rules: rules
+                .iter()
+                .map(|r| definition(r))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// The bundled rules, with fresh stats.
+    pub fn rules(&self) -> Result<Vec<Rule>, serde_json::Error> {
+        self.rules
+            .iter()
+            .map(|r| {
+                let mut rule: Rule = serde_json::from_value(r.clone())?;
+                rule.creation_date = Utc::now();
+                Ok(rule)
+            })
+            .collect()
+    }
+}
+
+/// The rule as JSON, without its stats.
+pub fn definition(rule: &Rule) -> Result<Value, serde_json::Error> {
+    let mut value = serde_json::to_value(rule)?;
+    if let Value::Object(ref mut fields) = value {
+        for field in STATS_FIELDS.iter() {
+            fields.remove(*field);
+        }
+    }
+    Ok(value)
+}
+
+/// What to do with a bundled rule whose name is already taken.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ImportMode {
+    Skip,
+    Rename,
+    Overwrite,
+}
+
+pub struct ImportReport {
+    pub added: Vec<String>,
+    pub skipped: Vec<String>,
+    pub renamed: Vec<(String, String)>,
+    pub overwritten: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn friendly(&self) -> String {
+        let mut lines = vec![format!(
+            "{} rules imported.",
+            self.added.len() + self.renamed.len() + self.overwritten.len()
+        )];
+        if !self.added.is_empty() {
+            lines.push(format!("Added: {}", self.added.join(", ")));
+        }
+        if !self.skipped.is_empty() {
+            lines.push(format!("Skipped (name taken): {}", self.skipped.join(", ")));
+        }
+        if !self.renamed.is_empty() {
+            lines.push(format!(
+                "Renamed: {}",
+                self.renamed
+                    .iter()
+                    .map(|(from, to)| format!("{} → {}", from, to))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+        if !self.overwritten.is_empty() {
+            lines.push(format!("Overwritten: {}", self.overwritten.join(", ")));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn round_trip_keeps_definitions_and_drops_stats() {
+        let mut rule: Rule = serde_json::from_value(json!({
+            "name": "wave",
+            "criterion": {"EmailRegex": "^wave\\d+@"},
+            "actions": ["NotifyZulip", {"Note": "Wave of {rule}"}],
+            "expiry": 1_900_000_000_000i64,
+            "tags": ["waves"],
+            "escalation": {"key": "Ip", "window_days": 3, "steps": [["Close"]]},
+        }))
+        .unwrap();
+        rule.match_count = 12;
+        rule.most_recent_caught = vec!["wave1".to_owned()];
+        rule.revision = 4;
+
+        let json = serde_json::to_string(&RuleBundle::new(&[&rule]).unwrap()).unwrap();
+        for field in STATS_FIELDS.iter() {
+            assert!(!json.contains(&format!("\"{}\"", field)), "{}", field);
+        }
+        let rules = RuleBundle::from_json(&json).unwrap().rules().unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(definition(&rules[0]).unwrap(), definition(&rule).unwrap());
+        assert_eq!(rules[0].match_count, 0);
+        assert_eq!(rules[0].revision, 1);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::event::User;
+use crate::signup::rules::{in_priority_order, Action, Rule};
+
+use chrono::{DateTime, Utc};
+
+/// Decides which rules match an enriched user and which actions they call for, without
+/// taking any of them. Taking them is up to the caller.
+pub struct RuleEngine<'l> {
+    lua_state: &'l rlua::Lua,
+}
+
+/// A rule that matched, with the actions planned for this match.
+pub struct PlannedMatch<'r> {
+    pub rule: &'r Rule,
+    pub actions: Vec<Action>,
+}
+
+/// The outcome of one rule on one user.
+pub enum RuleOutcome<'r> {
+    Match(PlannedMatch<'r>),
+    Error { rule: &'r Rule, error: rlua::Error },
+}
+
+/// The outcomes of the rules that matched or failed, in evaluation order.
+pub struct Evaluation<'r> {
+    pub outcomes: Vec<RuleOutcome<'r>>,
+    /// The `stop` rule that ended the evaluation, if any.
+    pub stopped_by: Option<&'r Rule>,
+}
+
+impl Evaluation<'_> {
+    pub fn matches(&self) -> impl Iterator<Item = &PlannedMatch<'_>> {
+        self.outcomes.iter().filter_map(|o| match o {
+            RuleOutcome::Match(m) => Some(m),
+            RuleOutcome::Error { .. } => None,
+        })
+    }
+}
+
+impl<'l> RuleEngine<'l> {
+    pub fn new(lua_state: &'l rlua::Lua) -> Self {
+        RuleEngine { lua_state }
+    }
+
+    /// Evaluates the rules in priority order until a `stop` rule matches. For rules with an
+    /// escalation, `match_number` is asked which match (starting at 1) this is from the
+    /// user's escalation key; it is where the caller counts and records matches.
+    pub fn evaluate<'r>(
+        &self,
+        rules: &'r [Rule],
+        user: &User,
+        now: DateTime<Utc>,
+        mut match_number: impl FnMut(&Rule, &str) -> usize,
+    ) -> Evaluation<'r> {
+        let mut outcomes = vec![];
+        for rule in in_priority_order(rules) {
+            match rule.applies_to(user, self.lua_state, now) {
+                Ok(true) => {
+                    let actions = match rule.escalation.as_ref().and_then(|e| e.key.value(user)) {
+                        Some(key) => rule.actions_for(match_number(rule, &key)),
+                        None => &rule.actions,
+                    };
+                    outcomes.push(RuleOutcome::Match(PlannedMatch {
+                        rule,
+                        actions: actions.clone(),
+                    }));
+                    if rule.stop {
+                        return Evaluation {
+                            outcomes,
+                            stopped_by: Some(rule),
+                        };
+                    }
+                }
+                Ok(false) => {}
+                Err(error) => outcomes.push(RuleOutcome::Error { rule, error }),
+            }
+        }
+        Evaluation {
+            outcomes,
+            stopped_by: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua;
+    use serde_json::json;
+
+    fn rule(name: &str, criterion: serde_json::Value, fields: serde_json::Value) -> Rule {
+        let mut value = json!({
+            "name": name,
+            "criterion": criterion,
+            "actions": ["NotifyZulip"],
+        });
+        if let (Some(value), Some(fields)) = (value.as_object_mut(), fields.as_object()) {
+            value.extend(fields.clone());
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn user() -> User {
+        User::from_json(
+            r#"{"username": "wave1", "email": "wave1@example.com", "ip": "10.0.0.1",
+                "userAgent": null, "fingerPrint": null, "geoip": null, "device": null}"#,
+        )
+        .unwrap()
+    }
+
+    fn names<'r>(evaluation: &Evaluation<'r>) -> Vec<&'r str> {
+        evaluation
+            .outcomes
+            .iter()
+            .map(|o| match o {
+                RuleOutcome::Match(m) => m.rule.name.as_str(),
+                RuleOutcome::Error { rule, .. } => rule.name.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_in_priority_order() {
+        let rules = vec![
+            rule(
+                "low",
+                json!({"EmailContains": "wave"}),
+                json!({"priority": -1}),
+            ),
+            rule("other", json!({"EmailContains": "other"}), json!({})),
+            rule(
+                "high",
+                json!({"UsernameContains": "wave"}),
+                json!({"priority": 5}),
+            ),
+            rule("default", json!({"IpMatch": "10.0.0.1"}), json!({})),
+        ];
+        let lua_state = lua::new_lua();
+        let evaluation =
+            RuleEngine::new(&lua_state).evaluate(&rules, &user(), Utc::now(), |_, _| 1);
+        assert_eq!(names(&evaluation), vec!["high", "default", "low"]);
+        assert!(evaluation.stopped_by.is_none());
+    }
+
+    #[test]
+    fn stops_at_the_first_matching_stop_rule() {
+        let rules = vec![
+            rule(
+                "first",
+                json!({"EmailContains": "wave"}),
+                json!({"priority": 2}),
+            ),
+            rule(
+                "stop",
+                json!({"EmailContains": "wave"}),
+                json!({"priority": 1, "stop": true}),
+            ),
+            rule("skipped", json!({"EmailContains": "wave"}), json!({})),
+        ];
+        let lua_state = lua::new_lua();
+        let evaluation =
+            RuleEngine::new(&lua_state).evaluate(&rules, &user(), Utc::now(), |_, _| 1);
+        assert_eq!(names(&evaluation), vec!["first", "stop"]);
+        assert_eq!(evaluation.stopped_by.map(|r| r.name.as_str()), Some("stop"));
+    }
+
+    #[test]
+    fn a_stop_rule_that_does_not_match_does_not_stop() {
+        let rules = vec![
+            rule(
+                "stop",
+                json!({"EmailContains": "other"}),
+                json!({"priority": 1, "stop": true}),
+            ),
+            rule("next", json!({"EmailContains": "wave"}), json!({})),
+        ];
+        let lua_state = lua::new_lua();
+        let evaluation =
+            RuleEngine::new(&lua_state).evaluate(&rules, &user(), Utc::now(), |_, _| 1);
+        assert_eq!(names(&evaluation), vec!["next"]);
+        assert!(evaluation.stopped_by.is_none());
+    }
+
+    #[test]
+    fn errors_are_reported_and_evaluation_goes_on() {
+        let rules = vec![
+            rule(
+                "broken",
+                json!({"Lua": "error('boom')"}),
+                json!({"priority": 1}),
+            ),
+            rule("next", json!({"Lua": "user:name() == 'wave1'"}), json!({})),
+        ];
+        let lua_state = lua::new_lua();
+        let evaluation =
+            RuleEngine::new(&lua_state).evaluate(&rules, &user(), Utc::now(), |_, _| 1);
+        assert_eq!(names(&evaluation), vec!["broken", "next"]);
+        assert!(matches!(evaluation.outcomes[0], RuleOutcome::Error { .. }));
+        assert_eq!(evaluation.matches().count(), 1);
+    }
+
+    #[test]
+    fn disabled_expired_and_susp_ip_rules_do_not_match() {
+        let rules = vec![
+            rule(
+                "disabled",
+                json!({"EmailContains": "wave"}),
+                json!({"enabled": false}),
+            ),
+            rule(
+                "expired",
+                json!({"EmailContains": "wave"}),
+                json!({"expiry": 1000}),
+            ),
+            rule(
+                "susp_ip",
+                json!({"EmailContains": "wave"}),
+                json!({"susp_ip": true}),
+            ),
+        ];
+        let lua_state = lua::new_lua();
+        let evaluation =
+            RuleEngine::new(&lua_state).evaluate(&rules, &user(), Utc::now(), |_, _| 1);
+        assert!(evaluation.outcomes.is_empty());
+    }
+
+    #[test]
+    fn escalation_picks_the_actions_of_the_match_number() {
+        let rules = vec![rule(
+            "escalating",
+            json!({"EmailContains": "wave"}),
+            json!({"escalation": {
+                "key": "EmailDomain",
+                "window_days": 7,
+                "steps": [["Shadowban"], ["Close"]],
+            }}),
+        )];
+        let lua_state = lua::new_lua();
+        let engine = RuleEngine::new(&lua_state);
+        let actions_at = |n: usize| {
+            let mut asked = None;
+            let evaluation = engine.evaluate(&rules, &user(), Utc::now(), |rule, key| {
+                asked = Some((rule.name.clone(), key.to_owned()));
+                n
+            });
+            assert_eq!(
+                asked,
+                Some(("escalating".to_owned(), "example.com".to_owned()))
+            );
+            let actions = evaluation.matches().next().unwrap().actions.clone();
+            actions
+        };
+        assert_eq!(actions_at(1), vec![Action::NotifyZulip]);
+        assert_eq!(actions_at(2), vec![Action::Shadowban]);
+        assert_eq!(actions_at(3), vec![Action::Close]);
+        assert_eq!(actions_at(10), vec![Action::Close]);
+    }
+
+    #[test]
+    fn rules_without_escalation_are_not_counted() {
+        let rules = vec![rule("plain", json!({"EmailContains": "wave"}), json!({}))];
+        let lua_state = lua::new_lua();
+        let evaluation =
+            RuleEngine::new(&lua_state).evaluate(&rules, &user(), Utc::now(), |_, _| {
+                panic!("no escalation to count")
+            });
+        assert_eq!(evaluation.matches().count(), 1);
+    }
+}
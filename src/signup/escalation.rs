@@ -0,0 +1,174 @@
+use crate::event::User;
+use crate::signup::rules::Action;
+use crate::signup::storage::write_atomically;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs::File, io::ErrorKind};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum EscalationKey {
+    Ip,
+    FingerPrint,
+    EmailDomain,
+}
+
+impl EscalationKey {
+    pub fn value(&self, user: &User) -> Option<String> {
+        match self {
+            EscalationKey::Ip => Some(user.ip.0.clone()),
+            EscalationKey::FingerPrint => user.finger_print.as_ref().map(|fp| fp.0.clone()),
+            EscalationKey::EmailDomain => user
+                .email
+                .0
+                .rsplit_once('@')
+                .map(|(_, domain)| domain.to_lowercase()),
+        }
+    }
+
+    pub fn friendly(&self) -> &'static str {
+        match self {
+            EscalationKey::Ip => "IP",
+            EscalationKey::FingerPrint => "fingerprint",
+            EscalationKey::EmailDomain => "email domain",
+        }
+    }
+}
+
+/// Actions taken on repeat matches from the same key within the window.
+/// `steps[0]` is taken on the second match, `steps[1]` on the third, and so on;
+/// the last step is repeated for any further matches.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Escalation {
+    pub key: EscalationKey,
+    pub window_days: i64,
+    pub steps: Vec<Vec<Action>>,
+}
+
+impl Escalation {
+    pub fn friendly(&self) -> String {
+        format!(
+            "Escalation by {} within {} days: {}",
+            self.key.friendly(),
+            self.window_days,
+            self.steps
+                .iter()
+                .enumerate()
+                .map(|(i, step)| format!("match {}: {:?}", i + 2, step))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Per-rule, per-key match dates, persisted so escalation survives restarts.
+pub struct MatchHistory {
+    matches: HashMap<String, HashMap<String, Vec<DateTime<Utc>>>>,
+    history_path: String,
+}
+
+impl MatchHistory {
+    pub fn new(history_path: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let matches = match File::open(&history_path) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(MatchHistory {
+            matches,
+            history_path,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        write_atomically(&self.history_path, &serde_json::to_vec(&self.matches)?)
+    }
+
+    /// The number of matches of `rule` for `key` within the window, including one at `now`.
+    /// The match is only remembered if `record` is set. Matches of the rule that fell out of
+    /// the window are forgotten, for every key.
+    pub fn count(
+        &mut self,
+        rule: &str,
+        key: &str,
+        window_days: i64,
+        now: DateTime<Utc>,
+        record: bool,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let window_start = now - Duration::days(window_days);
+        let keys = self.matches.entry(rule.to_owned()).or_default();
+        keys.retain(|_, dates| {
+            dates.retain(|d| *d >= window_start);
+            !dates.is_empty()
+        });
+        let count = keys.get(key).map_or(0, |dates| dates.len()) + 1;
+        if record {
+            keys.entry(key.to_owned()).or_default().push(now);
+            self.save()?;
+        }
+        Ok(count)
+    }
+
+    pub fn forget_rule(&mut self, rule: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.matches.remove(rule).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn history(name: &str) -> MatchHistory {
+        let path =
+            std::env::temp_dir().join(format!("escalation-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        MatchHistory::new(path.to_string_lossy().into_owned()).unwrap()
+    }
+
+    fn day(n: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 1, 12, 0, 0).unwrap() + Duration::days(n)
+    }
+
+    #[test]
+    fn counts_matches_within_the_window() {
+        let mut history = history("window");
+        assert_eq!(history.count("r", "k", 7, day(0), true).unwrap(), 1);
+        assert_eq!(history.count("r", "k", 7, day(1), true).unwrap(), 2);
+        assert_eq!(history.count("r", "other", 7, day(1), true).unwrap(), 1);
+        assert_eq!(history.count("s", "k", 7, day(1), true).unwrap(), 1);
+        assert_eq!(history.count("r", "k", 7, day(8), true).unwrap(), 2);
+        assert_eq!(history.count("r", "k", 7, day(20), true).unwrap(), 1);
+    }
+
+    #[test]
+    fn only_records_when_asked() {
+        let mut history = history("record");
+        assert_eq!(history.count("r", "k", 7, day(0), false).unwrap(), 1);
+        assert_eq!(history.count("r", "k", 7, day(0), false).unwrap(), 1);
+        assert_eq!(history.count("r", "k", 7, day(0), true).unwrap(), 1);
+        assert_eq!(history.count("r", "k", 7, day(0), false).unwrap(), 2);
+    }
+
+    #[test]
+    fn prunes_every_key_of_the_rule() {
+        let mut history = history("prune");
+        history.count("r", "a", 1, day(0), true).unwrap();
+        history.count("r", "b", 1, day(0), true).unwrap();
+        history.count("r", "c", 1, day(5), false).unwrap();
+        assert!(history.matches["r"].is_empty());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let mut history = history("restart");
+        history.count("r", "k", 7, day(0), true).unwrap();
+        let mut reloaded = MatchHistory::new(history.history_path.clone()).unwrap();
+        assert_eq!(reloaded.count("r", "k", 7, day(1), false).unwrap(), 2);
+        reloaded.forget_rule("r").unwrap();
+        assert_eq!(reloaded.count("r", "k", 7, day(1), false).unwrap(), 1);
+    }
+}
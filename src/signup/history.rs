@@ -0,0 +1,180 @@
+use crate::signup::rules::Rule;
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+};
+
+/// Append-only JSONL record of every change to a rule's definition.
+pub struct RuleHistory {
+    pub revisions: Vec<Revision>,
+    history_path: String,
+}
+
+impl RuleHistory {
+    pub fn new(history_path: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut revisions = vec![];
+        match File::open(&history_path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        revisions.push(serde_json::from_str(&line)?);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(RuleHistory {
+            revisions,
+            history_path,
+        })
+    }
+
+    pub fn record(&mut self, revision: Revision) -> Result<(), Box<dyn std::error::Error>> {
+        let mut f = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.history_path)?;
+        writeln!(f, "{}", serde_json::to_string(&revision)?)?;
+        self.revisions.push(revision);
+        Ok(())
+    }
+
+    pub fn of_rule(&self, name: &str) -> Vec<&Revision> {
+        self.revisions.iter().filter(|r| r.rule.eq(name)).collect()
+    }
+
+    pub fn find(&self, name: &str, revision: u32) -> Option<&Revision> {
+        self.revisions
+            .iter()
+            .rev()
+            .find(|r| r.rule.eq(name) && r.revision == revision)
+    }
+
+    /// One line per revision of the rule, with what changed since the revision before it.
+    pub fn friendly(&self, name: &str) -> Vec<String> {
+        let mut previous: Option<&Rule> = None;
+        let mut lines = vec![];
+        for revision in self.of_rule(name) {
+            let changes = match previous {
+                Some(previous) => diff(previous, &revision.snapshot),
+                None => vec![],
+            };
+            lines.push(format!(
+                "* **r{}** {:?} by {} at (UTC) {}{}",
+                revision.revision,
+                revision.change,
+                &revision.author,
+                revision.date.format("%d/%m/%Y %T"),
+                if changes.is_empty() {
+                    "".to_owned()
+                } else {
+                    format!("\n  * {}", changes.join("\n  * "))
+                }
+            ));
+            previous = Some(&revision.snapshot);
+        }
+        lines
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum Change {
+    Add,
+    Edit,
+    Enable,
+    Disable,
+    Renew,
+    Remove,
+    Restore(u32),
+    Reload,
+    Import,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Revision {
+    pub rule: String,
+    pub revision: u32,
+    #[serde(with = "ts_milliseconds")]
+    pub date: DateTime<Utc>,
+    pub author: String,
+    pub change: Change,
+    pub snapshot: Rule,
+}
+
+/// The differences in definition between two versions of a rule. Stats are ignored.
+pub fn diff(old: &Rule, new: &Rule) -> Vec<String> {
+    let mut changes = vec![];
+    let mut compare = |field: &str, old: String, new: String| {
+        if old != new {
+            changes.push(format!("{}: `{}` → `{}`", field, old, new));
+        }
+    };
+    compare(
+        "criterion",
+        old.criterion.friendly(),
+        new.criterion.friendly(),
+    );
+    compare(
+        "actions",
+        format!("{:?}", old.actions),
+        format!("{:?}", new.actions),
+    );
+    compare(
+        "escalation",
+        old.escalation
+            .as_ref()
+            .map(|e| e.friendly())
+            .unwrap_or_default(),
+        new.escalation
+            .as_ref()
+            .map(|e| e.friendly())
+            .unwrap_or_default(),
+    );
+    compare("delays", old.friendly_delays(), new.friendly_delays());
+    compare(
+        "hourly cap",
+        format!("{:?}", old.hourly_cap),
+        format!("{:?}", new.hourly_cap),
+    );
+    compare(
+        "expiry",
+        format!("{:?}", old.expiry),
+        format!("{:?}", new.expiry),
+    );
+    compare("enabled", old.enabled.to_string(), new.enabled.to_string());
+    compare("susp_ip", old.susp_ip.to_string(), new.susp_ip.to_string());
+    compare(
+        "priority",
+        old.priority.to_string(),
+        new.priority.to_string(),
+    );
+    compare("stop", old.stop.to_string(), new.stop.to_string());
+    compare(
+        "schedule",
+        old.schedule
+            .as_ref()
+            .map(|s| s.friendly())
+            .unwrap_or_default(),
+        new.schedule
+            .as_ref()
+            .map(|s| s.friendly())
+            .unwrap_or_default(),
+    );
+    compare(
+        "auto-renew",
+        format!("{:?}", old.auto_renew),
+        format!("{:?}", new.auto_renew),
+    );
+    compare("tags", old.tags.join(", "), new.tags.join(", "));
+    compare(
+        "note",
+        old.note.clone().unwrap_or_default(),
+        new.note.clone().unwrap_or_default(),
+    );
+    changes
+}
@@ -0,0 +1,175 @@
+use crate::signup::storage::write_atomically;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::{fs::File, io::ErrorKind};
+
+/// Caps the number of destructive actions taken per hour, both per rule and globally. The
+/// counts and the breaker are saved, so a restart doesn't reset them.
+pub struct ActionLimiter {
+    global_cap: usize,
+    default_rule_cap: usize,
+    state: LimiterState,
+    state_path: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LimiterState {
+    global: VecDeque<DateTime<Utc>>,
+    per_rule: HashMap<String, VecDeque<DateTime<Utc>>>,
+    tripped: bool,
+}
+
+#[derive(PartialEq)]
+pub enum LimitDecision {
+    Allowed,
+    RuleCapExceeded(usize),
+    GlobalCapExceeded(usize),
+    Tripped,
+}
+
+impl ActionLimiter {
+    pub fn new(
+        global_cap: usize,
+        default_rule_cap: usize,
+        state_path: String,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = match File::open(&state_path) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => LimiterState::default(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(ActionLimiter {
+            global_cap,
+            default_rule_cap,
+            state,
+            state_path,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        write_atomically(&self.state_path, &serde_json::to_vec(&self.state)?)
+    }
+
+    /// Whether destructive actions are on hold until someone resets the breaker.
+    pub fn tripped(&self) -> bool {
+        self.state.tripped
+    }
+
+    /// Checks whether `rule` may take `count` more destructive actions at `now`,
+    /// and counts them if so. Exceeding the global cap trips the breaker.
+    pub fn check(
+        &mut self,
+        rule: &str,
+        rule_cap: Option<usize>,
+        count: usize,
+        now: DateTime<Utc>,
+    ) -> LimitDecision {
+        if self.state.tripped {
+            return LimitDecision::Tripped;
+        }
+
+        let hour_ago = now - Duration::hours(1);
+        while self.state.global.front().is_some_and(|d| *d < hour_ago) {
+            self.state.global.pop_front();
+        }
+        let rule_window = self.state.per_rule.entry(rule.to_owned()).or_default();
+        while rule_window.front().is_some_and(|d| *d < hour_ago) {
+            rule_window.pop_front();
+        }
+
+        let rule_cap = rule_cap.unwrap_or(self.default_rule_cap);
+        if rule_window.len() + count > rule_cap {
+            self.state.per_rule.remove(rule);
+            return LimitDecision::RuleCapExceeded(rule_cap);
+        }
+        if self.state.global.len() + count > self.global_cap {
+            self.state.tripped = true;
+            return LimitDecision::GlobalCapExceeded(self.global_cap);
+        }
+
+        for _ in 0..count {
+            rule_window.push_back(now);
+            self.state.global.push_back(now);
+        }
+        LimitDecision::Allowed
+    }
+
+    pub fn reset(&mut self) {
+        self.state.tripped = false;
+        self.state.global.clear();
+        self.state.per_rule.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn limiter(name: &str, global_cap: usize, default_rule_cap: usize) -> ActionLimiter {
+        let path =
+            std::env::temp_dir().join(format!("limiter-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        ActionLimiter::new(
+            global_cap,
+            default_rule_cap,
+            path.to_string_lossy().into_owned(),
+        )
+        .unwrap()
+    }
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn rule_cap_refuses_and_forgets_the_rule() {
+        let mut limiter = limiter("rule-cap", 100, 2);
+        assert!(limiter.check("a", None, 2, at(0)) == LimitDecision::Allowed);
+        assert!(limiter.check("a", None, 1, at(1)) == LimitDecision::RuleCapExceeded(2));
+        // The rule's window was cleared, the global count was not.
+        assert!(limiter.check("a", None, 2, at(2)) == LimitDecision::Allowed);
+        assert!(limiter.check("a", Some(5), 1, at(3)) == LimitDecision::Allowed);
+        assert!(!limiter.tripped());
+    }
+
+    #[test]
+    fn rule_cap_counts_the_last_hour_only() {
+        let mut limiter = limiter("last-hour", 100, 1);
+        assert!(limiter.check("a", None, 1, at(0)) == LimitDecision::Allowed);
+        assert!(limiter.check("b", None, 1, at(30)) == LimitDecision::Allowed);
+        assert!(limiter.check("a", None, 1, at(61)) == LimitDecision::Allowed);
+    }
+
+    #[test]
+    fn global_cap_trips_until_reset() {
+        let mut limiter = limiter("global-cap", 2, 10);
+        assert!(limiter.check("a", None, 1, at(0)) == LimitDecision::Allowed);
+        assert!(limiter.check("b", None, 1, at(1)) == LimitDecision::Allowed);
+        assert!(limiter.check("c", None, 1, at(2)) == LimitDecision::GlobalCapExceeded(2));
+        assert!(limiter.tripped());
+        assert!(limiter.check("c", None, 1, at(120)) == LimitDecision::Tripped);
+        limiter.reset();
+        assert!(limiter.check("c", None, 1, at(121)) == LimitDecision::Allowed);
+    }
+
+    #[test]
+    fn tripped_breaker_survives_a_restart() {
+        let mut limiter = limiter("restart", 1, 10);
+        assert!(limiter.check("a", None, 1, at(0)) == LimitDecision::Allowed);
+        assert!(limiter.check("b", None, 1, at(1)) == LimitDecision::GlobalCapExceeded(1));
+        limiter.save().unwrap();
+
+        let mut reloaded = ActionLimiter::new(1, 10, limiter.state_path.clone()).unwrap();
+        assert!(reloaded.tripped());
+        reloaded.reset();
+        reloaded.save().unwrap();
+
+        let mut reloaded = ActionLimiter::new(1, 10, limiter.state_path.clone()).unwrap();
+        assert!(!reloaded.tripped());
+        assert!(reloaded.check("a", None, 1, at(2)) == LimitDecision::Allowed);
+        assert!(reloaded.check("a", None, 1, at(3)) == LimitDecision::GlobalCapExceeded(1));
+    }
+}
@@ -1 +1,12 @@
+pub mod actionlog;
+pub mod audit;
+pub mod bundle;
+pub mod engine;
+pub mod escalation;
+pub mod history;
+pub mod limiter;
+pub mod queue;
+pub mod rulefile;
 pub mod rules;
+pub mod schedule;
+pub mod storage;
@@ -0,0 +1,273 @@
+use crate::event::{Event, Username};
+use crate::signup::rules::Action;
+use crate::signup::storage::write_atomically;
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use futures::{
+    future::{loop_fn, Loop},
+    Future,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::ErrorKind, sync::mpsc::Sender, time::Instant};
+use tokio::timer::Delay;
+
+pub struct ActionQueue {
+    pub actions: Vec<ScheduledAction>,
+    queue_path: String,
+}
+
+impl ActionQueue {
+    pub fn new(queue_path: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let actions = match File::open(&queue_path) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(ActionQueue {
+            actions,
+            queue_path,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        write_atomically(&self.queue_path, &serde_json::to_vec(&self.actions)?)
+    }
+
+    pub fn schedule(
+        &mut self,
+        rule: String,
+        username: Username,
+        action: Action,
+        payload: Option<String>,
+        due: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.actions.push(ScheduledAction {
+            due,
+            rule,
+            username,
+            action,
+            payload,
+        });
+        self.save()
+    }
+
+    /// Removes and returns all actions that are due at `now`, in the order they are due. A
+    /// close waits for the other actions of its rule on the same user, so that they can be
+    /// taken before it. If the queue can't be saved without the due actions, they stay
+    /// queued, to be taken next time.
+    pub fn take_due(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ScheduledAction>, Box<dyn std::error::Error>> {
+        let is_due: Vec<bool> = self
+            .actions
+            .iter()
+            .map(|a| {
+                a.due <= now
+                    && !(a.action.eq(&Action::Close)
+                        && self.actions.iter().any(|other| {
+                            other.due > now
+                                && !other.action.eq(&Action::Close)
+                                && other.rule == a.rule
+                                && other.username == a.username
+                        }))
+            })
+            .collect();
+        if !is_due.contains(&true) {
+            return Ok(vec![]);
+        }
+        let mut is_due = is_due.into_iter();
+        let (mut due, pending): (Vec<ScheduledAction>, Vec<ScheduledAction>) = self
+            .actions
+            .drain(..)
+            .partition(|_| is_due.next().unwrap_or(false));
+        self.actions = pending;
+        if let Err(e) = self.save() {
+            self.actions.append(&mut due);
+            return Err(e);
+        }
+        due.sort_by_key(|a| a.due);
+        Ok(due)
+    }
+
+    pub fn cancel_user(&mut self, username: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let before = self.actions.len();
+        self.actions
+            .retain(|a| !a.username.0.eq_ignore_ascii_case(username));
+        let removed = before - self.actions.len();
+        if removed > 0 {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn cancel_rule(&mut self, rule: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let before = self.actions.len();
+        self.actions.retain(|a| !a.rule.eq(rule));
+        let removed = before - self.actions.len();
+        if removed > 0 {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledAction {
+    #[serde(with = "ts_milliseconds")]
+    pub due: DateTime<Utc>,
+    pub rule: String,
+    pub username: Username,
+    pub action: Action,
+    #[serde(default = "default_payload")]
+    pub payload: Option<String>,
+}
+
+fn default_payload() -> Option<String> {
+    None
+}
+
+/// Groups actions by rule and user, keeping their order but with closes last, so that each
+/// group can be taken one action after another.
+pub fn by_match(actions: Vec<ScheduledAction>) -> Vec<Vec<ScheduledAction>> {
+    let mut groups: Vec<Vec<ScheduledAction>> = vec![];
+    for action in actions {
+        match groups
+            .iter_mut()
+            .find(|g| g[0].rule == action.rule && g[0].username == action.username)
+        {
+            Some(group) => group.push(action),
+            None => groups.push(vec![action]),
+        }
+    }
+    for group in &mut groups {
+        group.sort_by_key(|a| a.action.eq(&Action::Close));
+    }
+    groups
+}
+
+pub fn queue_loop(event_tx: Sender<Event>) {
+    println!("Action queue loop started.");
+    tokio::spawn(loop_fn((), move |_| {
+        let event_tx2 = event_tx.clone();
+        Delay::new(Instant::now() + std::time::Duration::from_secs(5))
+            .and_then(move |_| {
+                event_tx2.send(Event::InternalRunDueActions).unwrap();
+                Ok(Loop::Continue(()))
+            })
+            .map_err(|e| println!("Err in queue_loop: {}", e))
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("queue-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn minute(n: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(n)
+    }
+
+    fn schedule(queue: &mut ActionQueue, user: &str, due: i64) {
+        queue
+            .schedule(
+                "r".to_owned(),
+                Username(user.to_owned()),
+                Action::Close,
+                None,
+                minute(due),
+            )
+            .unwrap();
+    }
+
+    fn users(actions: &[ScheduledAction]) -> Vec<&str> {
+        actions.iter().map(|a| a.username.0.as_str()).collect()
+    }
+
+    #[test]
+    fn takes_due_actions_in_order_and_saves_the_rest() {
+        let path = temp_path("due");
+        let mut queue = ActionQueue::new(path.clone()).unwrap();
+        schedule(&mut queue, "late", 10);
+        schedule(&mut queue, "second", 5);
+        schedule(&mut queue, "first", 1);
+        assert!(queue.take_due(minute(0)).unwrap().is_empty());
+        assert_eq!(
+            users(&queue.take_due(minute(5)).unwrap()),
+            vec!["first", "second"]
+        );
+        assert_eq!(
+            users(&ActionQueue::new(path).unwrap().actions),
+            vec!["late"]
+        );
+    }
+
+    #[test]
+    fn keeps_due_actions_if_the_queue_cannot_be_saved() {
+        let mut queue = ActionQueue::new(temp_path("unsaved")).unwrap();
+        schedule(&mut queue, "user", 1);
+        queue.queue_path = std::env::temp_dir()
+            .join("no-such-directory")
+            .join("queue.json")
+            .to_string_lossy()
+            .into_owned();
+        assert!(queue.take_due(minute(5)).is_err());
+        assert_eq!(users(&queue.actions), vec!["user"]);
+    }
+
+    #[test]
+    fn close_waits_for_the_other_actions_of_its_rule_and_user() {
+        let mut queue = ActionQueue::new(temp_path("close")).unwrap();
+        schedule(&mut queue, "user", 1);
+        queue
+            .schedule(
+                "r".to_owned(),
+                Username("user".to_owned()),
+                Action::EngineMark,
+                None,
+                minute(3),
+            )
+            .unwrap();
+        assert!(queue.take_due(minute(2)).unwrap().is_empty());
+        let due = queue.take_due(minute(3)).unwrap();
+        assert_eq!(due.len(), 2);
+        let groups = by_match(due);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0]
+                .iter()
+                .map(|a| a.action.clone())
+                .collect::<Vec<_>>(),
+            vec![Action::EngineMark, Action::Close]
+        );
+    }
+
+    #[test]
+    fn groups_actions_by_rule_and_user() {
+        let mut queue = ActionQueue::new(temp_path("groups")).unwrap();
+        schedule(&mut queue, "a", 1);
+        schedule(&mut queue, "b", 1);
+        schedule(&mut queue, "a", 2);
+        let groups = by_match(queue.take_due(minute(2)).unwrap());
+        assert_eq!(
+            groups.iter().map(|g| users(g)).collect::<Vec<_>>(),
+            vec![vec!["a", "a"], vec!["b"]]
+        );
+    }
+
+    #[test]
+    fn cancels_by_user_and_by_rule() {
+        let mut queue = ActionQueue::new(temp_path("cancel")).unwrap();
+        schedule(&mut queue, "User", 1);
+        schedule(&mut queue, "other", 1);
+        assert_eq!(queue.cancel_user("user").unwrap(), 1);
+        assert_eq!(queue.cancel_rule("r").unwrap(), 1);
+        assert!(queue.actions.is_empty());
+    }
+}
@@ -0,0 +1,444 @@
+use crate::lua;
+use crate::signup::bundle::definition;
+use crate::signup::rules::{Criterion, Rule};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::fmt;
+use toml_edit::{ArrayOfTables, DocumentMut, ImDocument, Item, Table};
+
+/// Fields holding dates, which are milliseconds in `rules.json` and RFC 3339 strings in
+/// rule files, as paths from the rule. [`deserialize_date`] reads both.
+const DATE_FIELDS: [&[&str]; 2] = [&["expiry"], &["schedule", "start"]];
+
+/// An error in a rule file, with the line it is on if it is known.
+pub struct RuleFileError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for RuleFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl fmt::Debug for RuleFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for RuleFileError {}
+
+/// Writes rule definitions, without stats, as a TOML rule file:
+///
+/// ```toml
+/// [[rule]]
+/// name = "wave"
+/// criterion = { EmailRegex = "(?i)^wave\\d+@" }
+/// actions = ["NotifyZulip", { Note = "Wave of {rule}" }]
+/// expiry = "2026-12-31T00:00:00Z"
+/// ```
+pub fn to_toml(rules: &[Rule]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut definitions = vec![];
+    for rule in rules {
+        let mut value = strip_nulls(definition(rule)?);
+        for path in DATE_FIELDS.iter() {
+            if let Some(field) = field_mut(&mut value, path) {
+                if let Some(date) = field
+                    .as_i64()
+                    .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+                {
+                    *field = Value::String(date.to_rfc3339());
+                }
+            }
+        }
+        definitions.push(value);
+    }
+    let mut file = Map::new();
+    file.insert("rule".to_owned(), Value::Array(definitions));
+    Ok(toml::to_string(&Value::Object(file))?)
+}
+
+/// Updates a TOML rule file to new definitions. The tables of unchanged rules are kept as
+/// they are, comments included, and a changed rule keeps the comments above it. Returns
+/// `None` if no definition changed, and fails if `text` has errors rather than overwrite
+/// a hand edit in progress.
+pub fn update_toml(
+    text: &str,
+    rules: &[Rule],
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let (old, errors) = parse(text);
+    if !errors.is_empty() {
+        let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        return Err(format!(
+            "the rule file has errors, fix them before saving: {}",
+            errors.join("; ")
+        )
+        .into());
+    }
+    let old_definitions = old
+        .iter()
+        .map(|(_, rule)| definition(rule))
+        .collect::<Result<Vec<_>, _>>()?;
+    let definitions = rules
+        .iter()
+        .map(definition)
+        .collect::<Result<Vec<_>, _>>()?;
+    if old_definitions == definitions {
+        return Ok(None);
+    }
+
+    let mut document: DocumentMut = text.parse()?;
+    let old_tables: Vec<Table> = match document.remove("rule") {
+        Some(Item::ArrayOfTables(tables)) => tables.into_iter().collect(),
+        _ => vec![],
+    };
+    let generated = match to_toml(rules)?.parse::<DocumentMut>()?.remove("rule") {
+        Some(Item::ArrayOfTables(tables)) => tables,
+        _ => ArrayOfTables::new(),
+    };
+    let mut tables = ArrayOfTables::new();
+    for ((rule, definition), generated) in rules.iter().zip(&definitions).zip(generated) {
+        let table = match old.iter().position(|(_, r)| r.name == rule.name) {
+            Some(i) if old_definitions[i] == *definition => old_tables[i].clone(),
+            Some(i) => {
+                let mut table = generated;
+                *table.decor_mut() = old_tables[i].decor().clone();
+                table
+            }
+            None => generated,
+        };
+        tables.push(table);
+    }
+    document.insert("rule", Item::ArrayOfTables(tables));
+    renumber(document.as_table_mut(), &mut 0);
+    Ok(Some(document.to_string()))
+}
+
+/// Tables are written in the order of their positions, which tables from different
+/// documents do not share.
+fn renumber(table: &mut Table, next: &mut usize) {
+    table.set_position(*next);
+    *next += 1;
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => renumber(table, next),
+            Item::ArrayOfTables(tables) => tables.iter_mut().for_each(|t| renumber(t, next)),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the rule definitions of a TOML rule file. Stats are left at their defaults.
+pub fn from_toml(text: &str) -> Result<Vec<Rule>, RuleFileError> {
+    let (rules, mut errors) = parse(text);
+    if errors.is_empty() {
+        Ok(rules.into_iter().map(|(_, rule)| rule).collect())
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Checks everything that can be checked offline: syntax, regexes, actions, unique names
+/// and that Lua criteria compile.
+pub fn validate(text: &str, lua_state: &rlua::Lua) -> Vec<RuleFileError> {
+    let (rules, mut errors) = parse(text);
+    for (i, (lines, rule)) in rules.iter().enumerate() {
+        if rules[..i].iter().any(|(_, r)| r.name == rule.name) {
+            errors.push(RuleFileError {
+                line: lines.name,
+                message: format!("duplicate rule name `{}`", rule.name),
+            });
+        }
+        if let Criterion::Lua(ref code) = rule.criterion {
+            if let Err(e) = lua::check_constraints_function(code, lua_state) {
+                errors.push(RuleFileError {
+                    line: lines.criterion,
+                    message: format!("Lua error in rule `{}`: {}", rule.name, e),
+                });
+            }
+        }
+    }
+    errors.sort_by_key(|e| e.line);
+    errors
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+/// Where a rule is in the file: the byte offset of its header, and the lines of the
+/// fields errors are reported on, falling back to the header's line.
+struct RuleLines {
+    start: usize,
+    name: Option<usize>,
+    criterion: Option<usize>,
+}
+
+/// The rules that could be read, with their lines, and the errors in the others.
+///
+/// The whole file is deserialized so that errors point at the field they are about. A rule
+/// with an error is blanked out, keeping every offset, and the rest is read again.
+fn parse(text: &str) -> (Vec<(RuleLines, Rule)>, Vec<RuleFileError>) {
+    let line_of = |offset: usize| text[..offset].matches('\n').count() + 1;
+    let document = match ImDocument::parse(text) {
+        Ok(document) => document,
+        Err(e) => {
+            let error = RuleFileError {
+                line: e.span().map(|span| line_of(span.start)),
+                message: e.message().trim().to_owned(),
+            };
+            return (vec![], vec![error]);
+        }
+    };
+    let mut entries: Vec<RuleLines> = vec![];
+    match document.get("rule") {
+        None => {}
+        Some(Item::ArrayOfTables(tables)) => {
+            for table in tables.iter() {
+                let start = table.span().map_or(0, |span| span.start);
+                let line_of_field = |item: Option<&Item>| {
+                    Some(line_of(
+                        item.and_then(Item::span).map_or(start, |s| s.start),
+                    ))
+                };
+                let criterion = table.get("criterion");
+                let lua = criterion
+                    .and_then(Item::as_table_like)
+                    .and_then(|c| c.get("Lua"));
+                entries.push(RuleLines {
+                    start,
+                    name: line_of_field(table.get("name")),
+                    criterion: line_of_field(lua.or(criterion)),
+                });
+            }
+        }
+        Some(item) => {
+            let error = RuleFileError {
+                line: item.span().map(|span| line_of(span.start)),
+                message: "`rule` has to be an array of tables: `[[rule]]`".to_owned(),
+            };
+            return (vec![], vec![error]);
+        }
+    }
+
+    let mut source = text.to_owned();
+    let mut blanked = vec![false; entries.len()];
+    let mut errors = vec![];
+    loop {
+        let e = match toml::from_str::<RuleFile>(&source) {
+            Ok(file) => {
+                let remaining = entries
+                    .into_iter()
+                    .zip(blanked)
+                    .filter(|(_, blanked)| !blanked)
+                    .map(|(lines, _)| lines);
+                return (remaining.zip(file.rule).collect(), errors);
+            }
+            Err(e) => e,
+        };
+        let offset = e.span().map(|span| span.start);
+        errors.push(RuleFileError {
+            line: offset.map(line_of),
+            message: e.message().trim().to_owned(),
+        });
+        let index = offset.and_then(|offset| entries.iter().rposition(|r| r.start <= offset));
+        match index {
+            Some(i) if !blanked[i] => {
+                let end = entries.get(i + 1).map_or(source.len(), |r| r.start);
+                source.replace_range(entries[i].start..end, &blank(&text[entries[i].start..end]));
+                blanked[i] = true;
+            }
+            _ => return (vec![], errors),
+        }
+    }
+}
+
+/// Spaces for everything but line breaks, so that offsets and lines stay the same.
+fn blank(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\n' => "\n".to_owned(),
+            c => " ".repeat(c.len_utf8()),
+        })
+        .collect()
+}
+
+/// Reads a date as milliseconds, the way `rules.json` stores it, or as a date string, the
+/// way rule files write it.
+pub fn deserialize_date<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Value::String(text)) => parse_date(&text).map(Some).map_err(de::Error::custom),
+        Some(value) => value
+            .as_i64()
+            .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+            .map(Some)
+            .ok_or_else(|| {
+                de::Error::custom(format!("invalid date `{}`, use e.g. `2026-12-31`", value))
+            }),
+    }
+}
+
+fn parse_date(text: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|d| d.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        })
+        .map_err(|_| format!("invalid date `{}`, use e.g. `2026-12-31`", text))
+}
+
+fn field_mut<'a>(value: &'a mut Value, path: &[&str]) -> Option<&'a mut Value> {
+    path.iter()
+        .try_fold(value, |value, key| value.get_mut(*key))
+}
+
+/// TOML has no null; a missing field means the same to serde.
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, strip_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(strip_nulls).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"# Waves of throwaway accounts.
+[[rule]] # the first wave
+name = "wave"
+criterion = { EmailRegex = "(?i)^wave\\d+@" } # case-insensitive
+actions = ["NotifyZulip", { Note = "Wave of {rule}" }]
+expiry = "2026-12-31"
+
+[[ rule ]]
+name = "night"
+actions = ["Shadowban"]
+priority = 2
+
+[rule.criterion]
+Lua = "user:country() == 'XX'"
+
+[rule.schedule]
+hours = { from = 22, to = 6 }
+start = "2026-01-01T00:00:00Z"
+"#;
+
+    fn lines(text: &str) -> Vec<(Option<usize>, String)> {
+        validate(text, &lua::new_lua())
+            .into_iter()
+            .map(|e| (e.line, e.message))
+            .collect()
+    }
+
+    #[test]
+    fn reads_rule_files() {
+        let rules = from_toml(FILE).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "wave");
+        assert_eq!(
+            rules[0].expiry.map(|d| d.to_rfc3339()),
+            Some("2026-12-31T00:00:00+00:00".to_owned())
+        );
+        assert_eq!(rules[1].priority, 2);
+        assert!(matches!(rules[1].criterion, Criterion::Lua(_)));
+        let schedule = rules[1].schedule.as_ref().unwrap();
+        assert!(schedule.hours.is_some() && schedule.start.is_some());
+        assert!(validate(FILE, &lua::new_lua()).is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let rules = from_toml(FILE).unwrap();
+        let written = to_toml(&rules).unwrap();
+        let read = from_toml(&written).unwrap();
+        let definitions = |rules: &[Rule]| {
+            rules
+                .iter()
+                .map(|r| definition(r).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(definitions(&read), definitions(&rules));
+    }
+
+    #[test]
+    fn errors_are_on_the_line_of_the_field() {
+        let text = FILE
+            .replace("(?i)^wave", "(")
+            .replace("priority = 2", "priority = \"high\"")
+            .replace("user:country() == 'XX'", "user:country( ==");
+        let errors = lines(&text);
+        assert_eq!(
+            errors.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            vec![Some(4), Some(11)]
+        );
+        assert!(errors[0].1.contains("regex parse error"));
+
+        let text = FILE.replace("user:country() == 'XX'", "user:country( ==");
+        assert_eq!(lines(&text)[0].0, Some(14));
+
+        let text = FILE.replace("name = \"night\"", "name = \"wave\"");
+        assert_eq!(
+            lines(&text),
+            vec![(Some(9), "duplicate rule name `wave`".to_owned())]
+        );
+
+        let text = FILE.replace("2026-12-31", "soon");
+        assert_eq!(lines(&text)[0].0, Some(6));
+    }
+
+    #[test]
+    fn syntax_errors_stop_the_reading() {
+        let errors = lines("[[rule]]\nname = \"a\nactions = []\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, Some(2));
+        assert!(from_toml("rule = 1").is_err());
+        assert!(from_toml("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn updates_keep_unchanged_rules_as_written() {
+        let mut rules = from_toml(FILE).unwrap();
+        assert!(update_toml(FILE, &rules).unwrap().is_none());
+
+        rules[1].enabled = false;
+        let updated = update_toml(FILE, &rules).unwrap().unwrap();
+        assert!(updated.starts_with(&FILE[..FILE.find("\n\n").unwrap()]));
+        assert!(updated.contains("enabled = false"));
+        assert!(!from_toml(&updated).unwrap()[1].enabled);
+
+        rules.remove(0);
+        let updated = update_toml(&updated, &rules).unwrap().unwrap();
+        assert!(!updated.contains("wave"));
+        assert_eq!(from_toml(&updated).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn updates_refuse_files_with_errors() {
+        let rules = from_toml(FILE).unwrap();
+        let text = FILE.replace("priority = 2", "priority = \"high\"");
+        let error = update_toml(&text, &rules).unwrap_err().to_string();
+        assert!(error.contains("line 11"), "{}", error);
+    }
+}
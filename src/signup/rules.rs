@@ -1,6 +1,12 @@
 use crate::event::Event;
 use crate::event::{FingerPrint, Ip, User, Username};
 use crate::lua;
+use crate::signup::bundle::{ImportMode, ImportReport};
+use crate::signup::escalation::Escalation;
+use crate::signup::history::{diff, Change, Revision, RuleHistory};
+use crate::signup::rulefile::deserialize_date;
+use crate::signup::schedule::Schedule;
+use crate::signup::storage::Storage;
 
 use chrono::Utc;
 use chrono::{serde::ts_milliseconds, serde::ts_milliseconds_option, DateTime};
@@ -11,115 +17,581 @@ use futures::{
 use regex::Regex;
 use rlua;
 use serde::{Deserialize, Serialize};
-use std::{
-    fs::{File, OpenOptions},
-    sync::mpsc::Sender,
-    time::Instant,
-};
+use std::{cell::Cell, rc::Rc, sync::mpsc::Sender, time::Instant};
 use tokio::timer::Delay;
+use urlencoding::encode;
 
+/// The bot's rules, kept in a [`Storage`], with the history of every change to them.
 pub struct SignupRulesManager {
-    pub rules: Vec<Rule>,
-    rules_path: String,
+    rules: Vec<Rule>,
+    history: RuleHistory,
+    load_warning: Option<String>,
+    storage: Rc<dyn Storage>,
+    /// The storage version of the rules the bot itself last wrote or read.
+    known_version: Cell<Option<u128>>,
+}
+
+/// What changed in a reload: added, removed and changed rule names, with the changes.
+pub struct ReloadReport {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<(String, Vec<String>)>,
+}
+
+impl ReloadReport {
+    pub fn friendly(&self) -> String {
+        if self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty() {
+            return "Rules reloaded, nothing changed.".to_owned();
+        }
+        let mut lines = vec!["Rules reloaded.".to_owned()];
+        if !self.added.is_empty() {
+            lines.push(format!("Added: {}", self.added.join(", ")));
+        }
+        if !self.removed.is_empty() {
+            lines.push(format!("Removed: {}", self.removed.join(", ")));
+        }
+        for (name, changes) in &self.changed {
+            lines.push(format!(
+                "Changed `{}`:\n  * {}",
+                name,
+                changes.join("\n  * ")
+            ));
+        }
+        lines.join("\n")
+    }
 }
 
 impl SignupRulesManager {
-    pub fn new(rules_path: String) -> Result<Self, Box<dyn std::error::Error>> {
-        let f = File::open(&rules_path)?;
-        let r = serde_json::from_reader(f)?;
+    pub fn new(
+        storage: Rc<dyn Storage>,
+        history_path: String,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (rules, load_warning) = storage.load_rules()?;
         Ok(SignupRulesManager {
-            rules: r,
-            rules_path: rules_path,
+            rules,
+            history: RuleHistory::new(history_path)?,
+            load_warning,
+            known_version: Cell::new(storage.version()),
+            storage,
         })
     }
 
+    /// Adds the rules of a bundle. Rules whose name is taken are skipped, renamed or
+    /// overwritten depending on `mode`; overwritten rules keep their stats.
+    pub fn import(
+        &mut self,
+        rules: Vec<Rule>,
+        mode: ImportMode,
+        author: &str,
+        lua_state: &rlua::Lua,
+    ) -> Result<ImportReport, Box<dyn std::error::Error>> {
+        validate_rules(&rules, lua_state)?;
+        let mut report = ImportReport {
+            added: vec![],
+            skipped: vec![],
+            renamed: vec![],
+            overwritten: vec![],
+        };
+        for mut rule in rules {
+            if rule.created_by.is_none() {
+                rule.created_by = Some(author.to_owned());
+            }
+            let index = match (self.rules.iter().position(|r| r.name == rule.name), mode) {
+                (Some(_), ImportMode::Skip) => {
+                    report.skipped.push(rule.name);
+                    continue;
+                }
+                (Some(index), ImportMode::Overwrite) => {
+                    rule.keep_stats_of(&self.rules[index]);
+                    report.overwritten.push(rule.name.clone());
+                    self.rules[index] = rule;
+                    index
+                }
+                (existing, _) => {
+                    if existing.is_some() {
+                        let original = rule.name.clone();
+                        rule.name = (2..)
+                            .map(|n| format!("{}-{}", &original, n))
+                            .find(|name| self.find_rule(name.clone()).is_none())
+                            .unwrap();
+                        report.renamed.push((original, rule.name.clone()));
+                    } else {
+                        report.added.push(rule.name.clone());
+                    }
+                    rule.revision = self
+                        .history
+                        .of_rule(&rule.name)
+                        .last()
+                        .map(|r| r.revision)
+                        .unwrap_or(0);
+                    self.rules.push(rule);
+                    self.rules.len() - 1
+                }
+            };
+            self.record_revision(index, Change::Import, author)?;
+        }
+        self.save()?;
+        Ok(report)
+    }
+
+    /// Whether the stored rules were modified by something other than the bot.
+    pub fn modified_externally(&self) -> bool {
+        self.storage.version() != self.known_version.get()
+    }
+
+    /// Replaces the rules with the stored ones, keeping the stats of rules that still exist.
+    /// The new rules are validated first; if anything is wrong, nothing is replaced.
+    pub fn reload(
+        &mut self,
+        lua_state: &rlua::Lua,
+    ) -> Result<ReloadReport, Box<dyn std::error::Error>> {
+        // Whatever happens, don't keep trying to reload the same version.
+        self.known_version.set(self.storage.version());
+        let mut new_rules = self.storage.reload_rules()?;
+        validate_rules(&new_rules, lua_state)?;
+
+        let mut report = ReloadReport {
+            added: vec![],
+            removed: vec![],
+            changed: vec![],
+        };
+        for rule in &self.rules {
+            if !new_rules.iter().any(|r| r.name == rule.name) {
+                report.removed.push(rule.name.clone());
+            }
+        }
+        for new in new_rules.iter_mut() {
+            match self.rules.iter().find(|r| r.name == new.name) {
+                Some(old) => {
+                    new.keep_stats_of(old);
+                    let changes = diff(old, new);
+                    if !changes.is_empty() {
+                        report.changed.push((new.name.clone(), changes));
+                    }
+                }
+                None => {
+                    new.revision = self
+                        .history
+                        .of_rule(&new.name)
+                        .last()
+                        .map(|r| r.revision)
+                        .unwrap_or(0);
+                    report.added.push(new.name.clone());
+                }
+            }
+        }
+
+        for name in &report.removed {
+            if let Some(index) = self.rules.iter().position(|r| r.name.eq(name)) {
+                self.record_revision(index, Change::Remove, "reload")?;
+            }
+        }
+        self.rules = new_rules;
+        for index in 0..self.rules.len() {
+            let name = &self.rules[index].name;
+            if report.added.contains(name) || report.changed.iter().any(|(n, _)| n == name) {
+                self.record_revision(index, Change::Reload, "reload")?;
+            }
+        }
+        // The definitions are the stored ones already.
+        self.save_stats()?;
+        Ok(report)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn history(&self) -> &RuleHistory {
+        &self.history
+    }
+
+    /// Set when loading the rules needs the moderators' attention, e.g. a backup was used.
+    pub fn load_warning(&self) -> Option<&str> {
+        self.load_warning.as_deref()
+    }
+
+    /// Records which expiry notice was posted for the rule. Saved by [`Self::save_stats`].
+    pub fn set_exp_notification(&mut self, name: &str, exp_notification: u8) {
+        if let Some(rule) = self.rules.iter_mut().find(|r| r.name == name) {
+            rule.exp_notification = exp_notification;
+        }
+    }
+
     pub fn find_rule(&self, name: String) -> Option<&Rule> {
         self.rules.iter().find(|r| r.name.eq(&name))
     }
 
+    /// Saves the rules after a definition changed.
     pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let f = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&self.rules_path)?;
-        serde_json::to_writer(f, &self.rules)?;
+        self.check_not_modified()?;
+        self.storage.save_rules(&self.rules)?;
+        self.known_version.set(self.storage.version());
+        Ok(())
+    }
+
+    /// Saves the rules after only their stats changed.
+    pub fn save_stats(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_not_modified()?;
+        self.storage.save_stats(&self.rules)?;
+        self.known_version.set(self.storage.version());
+        Ok(())
+    }
+
+    /// Refuses to write over rules that were changed outside the bot since it last read
+    /// them. The reload loop picks those up; in-memory stats survive the reload.
+    fn check_not_modified(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.modified_externally() {
+            return Err(Box::new(std::io::Error::other(
+                "the stored rules were changed outside the bot and have not been reloaded yet, \
+                 so nothing was saved. Reload them with `signup rules reload` and try again.",
+            )));
+        }
         Ok(())
     }
 
-    pub fn add_rule(&mut self, rule: Rule) -> Result<(), Box<dyn std::error::Error>> {
+    /// Bumps the revision of the rule at `index` and records it in the history.
+    fn record_revision(
+        &mut self,
+        index: usize,
+        change: Change,
+        author: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rule = &mut self.rules[index];
+        rule.revision += 1;
+        let revision = Revision {
+            rule: rule.name.clone(),
+            revision: rule.revision,
+            date: Utc::now(),
+            author: author.to_owned(),
+            change,
+            snapshot: rule.clone(),
+        };
+        self.history.record(revision)
+    }
+
+    pub fn add_rule(&mut self, rule: Rule, author: &str) -> Result<(), Box<dyn std::error::Error>> {
         if self.find_rule(rule.name.clone()).is_some() {
             return Err(Box::new(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Already a rule found with that name.",
             )));
         }
+        let mut rule = rule;
+        // A rule added again after being removed continues its revision numbering.
+        rule.revision = self
+            .history
+            .of_rule(&rule.name)
+            .last()
+            .map(|r| r.revision)
+            .unwrap_or(0);
         self.rules.push(rule);
+        self.record_revision(self.rules.len() - 1, Change::Add, author)?;
         self.save()
     }
 
-    pub fn remove_rule(&mut self, name: String) -> Result<bool, Box<dyn std::error::Error>> {
-        let before = self.rules.len();
-        self.rules.retain(|r| !r.name.eq(&name));
-        let after = self.rules.len();
-        self.save()?;
-        Ok(before != after)
+    pub fn remove_rule(
+        &mut self,
+        name: String,
+        author: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.rules.iter().position(|r| r.name.eq(&name)) {
+            Some(index) => {
+                self.record_revision(index, Change::Remove, author)?;
+                self.rules.remove(index);
+                self.save()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The names of all rules matched by `selection`.
+    pub fn select(
+        &self,
+        selection: &RuleSelection,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let re = match selection {
+            RuleSelection::Pattern(pattern) => match Regex::new(pattern) {
+                Ok(re) => Some(re),
+                _ => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "Invalid regex.",
+                    )))
+                }
+            },
+            RuleSelection::Tag(_) => None,
+        };
+        Ok(self
+            .rules
+            .iter()
+            .filter(|r| match (selection, &re) {
+                (RuleSelection::Tag(tag), _) => r.tags.contains(tag),
+                (_, Some(re)) => re.is_match(&r.name),
+                _ => false,
+            })
+            .map(|r| r.name.clone())
+            .collect())
     }
 
     fn enable_disable_rules(
         &mut self,
-        pattern: String,
+        selection: RuleSelection,
         enabled: bool,
+        author: &str,
     ) -> Result<i32, Box<dyn std::error::Error>> {
-        match Regex::new(&pattern) {
-            Ok(re) => {
-                let mut counter = 0;
-                for rule in &mut self.rules {
-                    if re.is_match(&rule.name) {
-                        counter += 1;
-                        rule.enabled = enabled;
-                    }
+        let names = self.select(&selection)?;
+        let mut counter = 0;
+        for index in 0..self.rules.len() {
+            if names.contains(&self.rules[index].name) {
+                counter += 1;
+                if self.rules[index].enabled != enabled {
+                    self.rules[index].enabled = enabled;
+                    self.record_revision(
+                        index,
+                        if enabled {
+                            Change::Enable
+                        } else {
+                            Change::Disable
+                        },
+                        author,
+                    )?;
                 }
-                self.save()?;
-                Ok(counter)
             }
-            _ => Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Invalid regex.",
-            ))),
         }
+        self.save()?;
+        Ok(counter)
     }
 
-    pub fn disable_rules(&mut self, pattern: String) -> Result<i32, Box<dyn std::error::Error>> {
-        self.enable_disable_rules(pattern, false)
+    pub fn disable_rules(
+        &mut self,
+        selection: RuleSelection,
+        author: &str,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        self.enable_disable_rules(selection, false, author)
     }
 
-    pub fn enable_rules(&mut self, pattern: String) -> Result<i32, Box<dyn std::error::Error>> {
-        self.enable_disable_rules(pattern, true)
+    pub fn enable_rules(
+        &mut self,
+        selection: RuleSelection,
+        author: &str,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        self.enable_disable_rules(selection, true, author)
+    }
+
+    /// Removes the rules matched by `selection` and returns their names. The rules are saved
+    /// once, so the newest backup holds them all as they were before.
+    pub fn remove_rules(
+        &mut self,
+        selection: RuleSelection,
+        author: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let names = self.select(&selection)?;
+        for index in 0..self.rules.len() {
+            if names.contains(&self.rules[index].name) {
+                self.record_revision(index, Change::Remove, author)?;
+            }
+        }
+        self.rules.retain(|r| !names.contains(&r.name));
+        self.save()?;
+        Ok(names)
+    }
+
+    /// Sets the expiry of the rules matched by `selection`, saving once like
+    /// [`Self::remove_rules`].
+    pub fn renew_rules(
+        &mut self,
+        selection: RuleSelection,
+        expiry: DateTime<Utc>,
+        author: &str,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        let names = self.select(&selection)?;
+        let mut counter = 0;
+        for index in 0..self.rules.len() {
+            if names.contains(&self.rules[index].name) {
+                counter += 1;
+                self.rules[index].expiry = Some(expiry);
+                self.record_revision(index, Change::Renew, author)?;
+            }
+        }
+        self.save()?;
+        Ok(counter)
+    }
+
+    pub fn edit_rule(
+        &mut self,
+        name: &str,
+        edit: RuleEdit,
+        author: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.rules.iter().position(|r| r.name.eq(name)) {
+            Some(index) => {
+                self.rules[index].apply_edit(edit);
+                self.record_revision(index, Change::Edit, author)?;
+                self.save()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn disable_rule(
+        &mut self,
+        name: &str,
+        author: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.rules.iter().position(|r| r.name.eq(name)) {
+            Some(index) => {
+                self.rules[index].enabled = false;
+                self.record_revision(index, Change::Disable, author)?;
+                self.save()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
     pub fn renew(
         &mut self,
         rule_name: String,
         expiry: DateTime<Utc>,
+        author: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        for rule in &mut self.rules {
-            if rule.name == rule_name {
-                rule.expiry = Some(expiry);
-                break;
-            }
+        if let Some(index) = self.rules.iter().position(|r| r.name == rule_name) {
+            self.rules[index].expiry = Some(expiry);
+            self.record_revision(index, Change::Renew, author)?;
         }
         self.save()?;
         Ok(())
     }
 
-    pub fn list_names(&self) -> Vec<String> {
+    /// Brings back the definition the rule had at `revision`. If the rule still exists,
+    /// its stats are kept; if it was removed, it is added again.
+    pub fn restore(
+        &mut self,
+        name: &str,
+        revision: u32,
+        author: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut restored = match self.history.find(name, revision) {
+            Some(r) => r.snapshot.clone(),
+            None => return Ok(false),
+        };
+        let index = match self.rules.iter().position(|r| r.name.eq(name)) {
+            Some(index) => {
+                let current = &self.rules[index];
+                restored.match_count = current.match_count;
+                restored.most_recent_caught = current.most_recent_caught.clone();
+                restored.latest_match_date = current.latest_match_date;
+                restored.exp_notification = current.exp_notification;
+                restored.stale_since = current.stale_since;
+                restored.revision = current.revision;
+                self.rules[index] = restored;
+                index
+            }
+            None => {
+                restored.revision = self
+                    .history
+                    .of_rule(name)
+                    .last()
+                    .map(|r| r.revision)
+                    .unwrap_or(revision);
+                self.rules.push(restored);
+                self.rules.len() - 1
+            }
+        };
+        self.record_revision(index, Change::Restore(revision), author)?;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// The rules in the order they are evaluated: highest priority first, and in file order
+    /// among rules with the same priority.
+    pub fn in_priority_order(&self) -> Vec<&Rule> {
+        in_priority_order(&self.rules)
+    }
+
+    /// Flags enabled rules that have not matched in `stale_days`, unflags the ones that have,
+    /// and, if `grace_days` is set, disables the rules that have been flagged for longer than
+    /// that. Returns the names of the disabled rules.
+    pub fn check_stale(
+        &mut self,
+        stale_days: i64,
+        grace_days: Option<i64>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut to_disable = vec![];
+        let mut flags_changed = false;
+        for rule in self.rules.iter_mut() {
+            if !rule.enabled || rule.last_activity() > now - chrono::Duration::days(stale_days) {
+                flags_changed |= rule.stale_since.take().is_some();
+                continue;
+            }
+            match (rule.stale_since, grace_days) {
+                (None, _) => {
+                    rule.stale_since = Some(now);
+                    flags_changed = true;
+                }
+                (Some(since), Some(grace)) if since < now - chrono::Duration::days(grace) => {
+                    to_disable.push(rule.name.clone())
+                }
+                _ => {}
+            }
+        }
+        for name in &to_disable {
+            if let Some(index) = self.rules.iter().position(|r| r.name.eq(name)) {
+                self.rules[index].enabled = false;
+                self.rules[index].stale_since = None;
+                self.record_revision(index, Change::Disable, "stale check")?;
+            }
+        }
+        if !to_disable.is_empty() {
+            self.save()?;
+        } else if flags_changed {
+            self.save_stats()?;
+        }
+        Ok(to_disable)
+    }
+
+    pub fn stale_rules(&self) -> Vec<&Rule> {
         self.rules
             .iter()
+            .filter(|r| r.stale_since.is_some())
+            .collect()
+    }
+
+    pub fn list_names(&self, tag: Option<&str>) -> Vec<String> {
+        self.in_priority_order()
+            .into_iter()
+            .filter(|r| tag.is_none_or(|t| r.tags.iter().any(|rt| rt == t)))
             .map(|r| {
-                if r.enabled {
+                let name = if r.enabled {
                     r.name.clone()
                 } else {
                     format!("({})", &r.name)
+                };
+                let name = match (r.priority, r.stop) {
+                    (0, false) => name,
+                    (0, true) => format!("{} (stop)", name),
+                    (priority, false) => format!("{} (p{})", name, priority),
+                    (priority, true) => format!("{} (p{}, stop)", name, priority),
+                };
+                let name = if r.tags.is_empty() {
+                    name
+                } else {
+                    format!("{} #{}", name, r.tags.join(" #"))
+                };
+                match (&r.created_by, &r.note) {
+                    (None, None) => name,
+                    (created_by, note) => format!(
+                        "{} [{}{}]",
+                        name,
+                        created_by.as_deref().unwrap_or("unknown"),
+                        note.as_ref()
+                            .map(|n| format!(": {}", n))
+                            .unwrap_or_default()
+                    ),
                 }
             })
             .collect()
@@ -150,40 +622,129 @@ impl SignupRulesManager {
 
             rule.match_count += 1;
             rule.latest_match_date = Some(Utc::now());
+            rule.stale_since = None;
+            if let Some(period) = rule.auto_renew {
+                let renewed = Utc::now() + chrono::Duration::seconds(period);
+                if rule.expiry.is_some_and(|expiry| expiry < renewed) {
+                    rule.expiry = Some(renewed);
+                    rule.exp_notification = 0;
+                }
+            }
             let mrc = &mut rule.most_recent_caught;
-            let Username(user) = user;
-            mrc.push(user.to_owned());
+            mrc.push(user.0.to_owned());
             if mrc.len() > 3 {
                 mrc.remove(0);
             }
         }
-        self.save()
+        self.check_not_modified()?;
+        self.storage
+            .save_match(&self.rules, index, user, Utc::now())?;
+        self.known_version.set(self.storage.version());
+        Ok(())
+    }
+}
+
+/// The order rules are evaluated in: highest priority first, ties in file order.
+pub fn in_priority_order(rules: &[Rule]) -> Vec<&Rule> {
+    let mut rules: Vec<&Rule> = rules.iter().collect();
+    rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    rules
+}
+
+/// Checks what serde can't: unique names and Lua snippets that compile.
+pub fn validate_rules(rules: &[Rule], lua_state: &rlua::Lua) -> Result<(), String> {
+    for (i, rule) in rules.iter().enumerate() {
+        if rules[..i].iter().any(|r| r.name == rule.name) {
+            return Err(format!("Duplicate rule name `{}`.", rule.name));
+        }
+        if let Criterion::Lua(ref code) = rule.criterion {
+            lua::check_constraints_function(code, lua_state)
+                .map_err(|e| format!("Lua error in rule `{}`: {}", rule.name, e))?;
+        }
     }
+    Ok(())
 }
 
+/// A named criterion with the actions to take on the signups that meet it, plus its
+/// settings and match stats. See [`Rule::applies_to`] for when it matches.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Rule {
+    /// Unique among the rules; commands and the history refer to the rule by it.
     pub name: String,
+    /// What a signup has to look like to match.
     pub criterion: Criterion,
+    /// What to do on a match, or on a first match if there is an `escalation`.
     pub actions: Vec<Action>,
+    /// Stat: how many signups matched.
     #[serde(default = "default_match_count")]
     pub match_count: usize,
+    /// Stat: the last three usernames that matched, oldest first.
     #[serde(default = "default_mrc")]
     pub most_recent_caught: Vec<String>,
+    /// Takes every action at once, ignoring `delay` and `action_delays`.
     #[serde(default = "default_nodelay")]
     pub no_delay: bool,
+    /// A disabled rule never matches.
     #[serde(default = "default_enabled")]
     pub enabled: bool,
+    /// Only matches signups whose IP Lichess considers suspicious.
     #[serde(default = "default_ip_susp")]
     pub susp_ip: bool,
-    #[serde(with = "ts_milliseconds_option", default = "default_expiry")]
+    /// The rule stops matching from then on and is removed three days later.
+    #[serde(
+        serialize_with = "ts_milliseconds_option::serialize",
+        deserialize_with = "deserialize_date",
+        default = "default_expiry"
+    )]
     pub expiry: Option<chrono::DateTime<Utc>>,
+    /// Stat: which expiry notice was posted: 0 none, 1 expiring soon, 2 expired.
     #[serde(default = "default_exp_notification")]
     pub exp_notification: u8,
+    /// Stat: when the rule was added.
     #[serde(with = "ts_milliseconds", default = "default_creation_date")]
     pub creation_date: DateTime<Utc>,
+    /// Stat: when a signup last matched.
     #[serde(with = "ts_milliseconds_option", default = "default_latest_match_date")]
     pub latest_match_date: Option<DateTime<Utc>>,
+    /// How long to wait before the actions that are delayed by default.
+    #[serde(default = "default_delay")]
+    pub delay: Option<DelayRange>,
+    /// Delays for single actions, which win over `delay`.
+    #[serde(default = "default_action_delays")]
+    pub action_delays: Vec<ActionDelay>,
+    /// Destructive actions the rule may take per hour, instead of the bot-wide default.
+    #[serde(default = "default_hourly_cap")]
+    pub hourly_cap: Option<usize>,
+    /// Other actions for repeat matches from the same IP, fingerprint or email domain.
+    #[serde(default = "default_escalation")]
+    pub escalation: Option<Escalation>,
+    /// Stat: incremented on every change to the definition.
+    #[serde(default = "default_revision")]
+    pub revision: u32,
+    /// Who added the rule.
+    #[serde(default = "default_created_by")]
+    pub created_by: Option<String>,
+    /// Free text for the moderators.
+    #[serde(default = "default_note")]
+    pub note: Option<String>,
+    /// Labels to renew or remove rules together.
+    #[serde(default = "default_tags")]
+    pub tags: Vec<String>,
+    /// Higher priorities are evaluated first; equal ones keep their order.
+    #[serde(default = "default_priority")]
+    pub priority: i32,
+    /// A match stops the evaluation of the rules after this one.
+    #[serde(default = "default_stop")]
+    pub stop: bool,
+    /// When the rule is active; always if unset.
+    #[serde(default = "default_schedule")]
+    pub schedule: Option<Schedule>,
+    /// If set, every match pushes the expiry to at least this many seconds from now.
+    #[serde(default = "default_auto_renew")]
+    pub auto_renew: Option<i64>,
+    /// Stat: when the rule was flagged for not matching in a while, if it is.
+    #[serde(with = "ts_milliseconds_option", default = "default_stale_since")]
+    pub stale_since: Option<DateTime<Utc>>,
 }
 
 fn default_match_count() -> usize {
@@ -221,16 +782,263 @@ fn default_expiry() -> Option<DateTime<Utc>> {
     None
 }
 
+fn default_delay() -> Option<DelayRange> {
+    None
+}
+
+fn default_action_delays() -> Vec<ActionDelay> {
+    vec![]
+}
+
+fn default_hourly_cap() -> Option<usize> {
+    None
+}
+
+fn default_escalation() -> Option<Escalation> {
+    None
+}
+
+fn default_revision() -> u32 {
+    1
+}
+
+fn default_created_by() -> Option<String> {
+    None
+}
+
+fn default_note() -> Option<String> {
+    None
+}
+
+fn default_tags() -> Vec<String> {
+    vec![]
+}
+
+fn default_priority() -> i32 {
+    0
+}
+
+fn default_stop() -> bool {
+    false
+}
+
+fn default_schedule() -> Option<Schedule> {
+    None
+}
+
+fn default_auto_renew() -> Option<i64> {
+    None
+}
+
+fn default_stale_since() -> Option<DateTime<Utc>> {
+    None
+}
+
+/// A group of rules to operate on at once: either by name regex or by tag.
+#[derive(Deserialize, Clone)]
+pub enum RuleSelection {
+    Pattern(String),
+    Tag(String),
+}
+
+/// A change to the definition of an existing rule. Stats are left untouched.
+#[derive(Deserialize, Clone)]
+pub enum RuleEdit {
+    Criterion(Criterion),
+    Actions {
+        actions: Vec<Action>,
+        action_delays: Vec<ActionDelay>,
+        escalation: Option<Escalation>,
+    },
+    Expiry(Option<DateTime<Utc>>),
+    Delay(Option<DelayRange>),
+    HourlyCap(Option<usize>),
+    NoDelay(bool),
+    SuspIp(bool),
+    Note(Option<String>),
+    Tags(Vec<String>),
+    Priority(i32),
+    Stop(bool),
+    Schedule(Option<Schedule>),
+    AutoRenew(Option<i64>),
+}
+
 impl Rule {
-    pub fn has_expired(&self) -> bool {
+    pub fn apply_edit(&mut self, edit: RuleEdit) {
+        match edit {
+            RuleEdit::Criterion(criterion) => self.criterion = criterion,
+            RuleEdit::Actions {
+                actions,
+                action_delays,
+                escalation,
+            } => {
+                self.actions = actions;
+                self.action_delays = action_delays;
+                self.escalation = escalation;
+            }
+            RuleEdit::Expiry(expiry) => {
+                self.expiry = expiry;
+                self.exp_notification = 0;
+            }
+            RuleEdit::Delay(delay) => self.delay = delay,
+            RuleEdit::HourlyCap(cap) => self.hourly_cap = cap,
+            RuleEdit::NoDelay(no_delay) => self.no_delay = no_delay,
+            RuleEdit::SuspIp(susp_ip) => self.susp_ip = susp_ip,
+            RuleEdit::Note(note) => self.note = note,
+            RuleEdit::Tags(tags) => self.tags = tags,
+            RuleEdit::Priority(priority) => self.priority = priority,
+            RuleEdit::Stop(stop) => self.stop = stop,
+            RuleEdit::Schedule(schedule) => self.schedule = schedule,
+            RuleEdit::AutoRenew(period) => self.auto_renew = period,
+        }
+    }
+
+    /// The latest match, or the creation date for rules that never matched.
+    pub fn last_activity(&self) -> DateTime<Utc> {
+        self.latest_match_date.unwrap_or(self.creation_date)
+    }
+
+    pub fn has_expired_at(&self, now: DateTime<Utc>) -> bool {
         if let Some(expiry) = self.expiry {
-            Utc::now() > expiry
+            now > expiry
         } else {
             false
         }
     }
+
+    /// Takes over the stats of the rule this one replaces.
+    pub fn keep_stats_of(&mut self, old: &Rule) {
+        self.match_count = old.match_count;
+        self.most_recent_caught = old.most_recent_caught.clone();
+        self.latest_match_date = old.latest_match_date;
+        self.exp_notification = old.exp_notification;
+        self.stale_since = old.stale_since;
+        self.creation_date = old.creation_date;
+        self.revision = old.revision;
+    }
+
+    /// Whether the rule's schedule, if any, allows it to match at `now`.
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        self.schedule.as_ref().is_none_or(|s| s.is_active_at(now))
+    }
+
+    /// Whether the rule matches the user at `now`: it has to be enabled, unexpired, within
+    /// its schedule and, for `susp_ip` rules, see a suspicious IP before its criterion counts.
+    pub fn applies_to(
+        &self,
+        user: &User,
+        lua_state: &rlua::Lua,
+        now: DateTime<Utc>,
+    ) -> Result<bool, rlua::Error> {
+        if !self.enabled
+            || self.has_expired_at(now)
+            || !self.is_active_at(now)
+            || (self.susp_ip && !user.susp_ip)
+        {
+            Ok(false)
+        } else {
+            self.criterion.take_action(user, lua_state)
+        }
+    }
+
+    /// The actions to take on the `match_number`th match (starting at 1) from the same
+    /// escalation key.
+    pub fn actions_for(&self, match_number: usize) -> &Vec<Action> {
+        match &self.escalation {
+            Some(escalation) if match_number > 1 && !escalation.steps.is_empty() => {
+                let step = (match_number - 2).min(escalation.steps.len() - 1);
+                &escalation.steps[step]
+            }
+            _ => &self.actions,
+        }
+    }
+
+    /// The delay range to wait before taking `action`, or `None` if it should be taken at once.
+    /// A per-action delay wins over the rule's delay, which only applies to the actions that
+    /// are delayed by default.
+    pub fn action_delay(&self, action: &Action) -> Option<DelayRange> {
+        if self.no_delay {
+            return None;
+        }
+        if let Some(action_delay) = self.action_delays.iter().find(|d| d.action.eq(action)) {
+            return Some(action_delay.delay);
+        }
+        if action.delayed_by_default() {
+            Some(self.delay.unwrap_or(DEFAULT_DELAY))
+        } else {
+            None
+        }
+    }
+
+    pub fn friendly_delays(&self) -> String {
+        if self.no_delay {
+            return "No delay".to_owned();
+        }
+        let mut parts = vec![format!(
+            "Delay: {}",
+            self.delay.unwrap_or(DEFAULT_DELAY).friendly()
+        )];
+        for action_delay in &self.action_delays {
+            parts.push(format!(
+                "{:?} delay: {}",
+                action_delay.action,
+                action_delay.delay.friendly()
+            ));
+        }
+        parts.join(". ")
+    }
+}
+
+pub const DEFAULT_DELAY: DelayRange = DelayRange {
+    min_secs: 30,
+    max_secs: 100,
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct DelayRange {
+    pub min_secs: u64,
+    pub max_secs: u64,
+}
+
+impl DelayRange {
+    /// Picks a duration within the range. `roll` is in `[0, 1)`, so that all actions of one
+    /// match that share a range are taken at the same moment.
+    pub fn pick(&self, roll: f64) -> std::time::Duration {
+        let spread = self.max_secs.saturating_sub(self.min_secs) as f64;
+        std::time::Duration::from_millis(((self.min_secs as f64 + spread * roll) * 1000.0) as u64)
+    }
+
+    pub fn friendly(&self) -> String {
+        format!(
+            "{}-{}",
+            friendly_secs(self.min_secs),
+            friendly_secs(self.max_secs)
+        )
+    }
 }
 
+fn friendly_secs(secs: u64) -> String {
+    if secs == 0 {
+        "0s".to_owned()
+    } else if secs.is_multiple_of(86400) {
+        format!("{}d", secs / 86400)
+    } else if secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ActionDelay {
+    pub action: Action,
+    pub delay: DelayRange,
+}
+
+/// What a signup has to look like for a rule to match. Regexes are case-sensitive; the
+/// `Contains` criteria are not.
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Criterion {
     IpMatch(Ip),
@@ -244,6 +1052,7 @@ pub enum Criterion {
 }
 
 impl Criterion {
+    /// Whether the user meets the criterion. Only `Lua` criteria can fail.
     pub fn take_action(&self, user: &User, lua_state: &rlua::Lua) -> Result<bool, rlua::Error> {
         Ok(match self {
             Criterion::IpMatch(exact) => exact.eq(&user.ip),
@@ -287,6 +1096,8 @@ impl Criterion {
     }
 }
 
+/// What a matching rule does: a moderation action through the Lichess API, a Zulip
+/// notification or a webhook.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum Action {
     Shadowban,
@@ -297,6 +1108,14 @@ pub enum Action {
     Alt,
     EnableChatPanic,
     NotifyZulip,
+    Note(String),
+    Warn(String),
+    Report(String),
+    KidMode,
+    Webhook {
+        url: String,
+        template: Option<String>,
+    },
 }
 
 impl Action {
@@ -316,8 +1135,121 @@ impl Action {
             Action::Alt => Some(format!("https://lichess.org/mod/{}/alt/true", username.0)),
             Action::EnableChatPanic => Some(String::from("https://lichess.org/mod/chat-panic")),
             Action::NotifyZulip => None,
+            Action::Note(_) => Some(format!("https://lichess.org/api/user/{}/note", username.0)),
+            Action::Warn(subject) => Some(format!(
+                "https://lichess.org/mod/{}/warn?subject={}",
+                username.0,
+                encode(subject)
+            )),
+            Action::Report(_) => Some(String::from("https://lichess.org/report")),
+            Action::KidMode => Some(format!("https://lichess.org/mod/{}/kid/true", username.0)),
+            Action::Webhook { .. } => None,
         }
     }
+
+    /// The form body to send to the endpoint, with `{rule}` and `{user}` filled in.
+    pub fn api_form(&self, rule: &str, username: &Username) -> Option<String> {
+        match self {
+            Action::Note(template) => Some(format!(
+                "text={}&mod=true",
+                encode(&render_template(template, rule, username))
+            )),
+            Action::Report(template) => Some(format!(
+                "username={}&reason=other&text={}",
+                encode(&username.0),
+                encode(&render_template(template, rule, username))
+            )),
+            _ => None,
+        }
+    }
+
+    /// The endpoint that undoes the action, if it can be undone.
+    pub fn inverse_endpoint(&self, username: &Username) -> Option<String> {
+        match self {
+            Action::Shadowban => Some(format!(
+                "https://lichess.org/mod/{}/troll/false",
+                username.0
+            )),
+            Action::EngineMark => Some(format!(
+                "https://lichess.org/mod/{}/engine/false",
+                username.0
+            )),
+            Action::BoostMark => Some(format!(
+                "https://lichess.org/mod/{}/booster/false",
+                username.0
+            )),
+            Action::IpBan => Some(format!("https://lichess.org/mod/{}/ban/false", username.0)),
+            Action::Alt => Some(format!("https://lichess.org/mod/{}/alt/false", username.0)),
+            Action::KidMode => Some(format!("https://lichess.org/mod/{}/kid/false", username.0)),
+            Action::Close
+            | Action::EnableChatPanic
+            | Action::NotifyZulip
+            | Action::Note(_)
+            | Action::Warn(_)
+            | Action::Report(_)
+            | Action::Webhook { .. } => None,
+        }
+    }
+
+    /// Whether the action is carried out by the bot itself rather than by an HTTP request.
+    pub fn is_local(&self) -> bool {
+        self.eq(&Action::NotifyZulip)
+    }
+
+    /// The JSON body posted by a webhook action, prepared when the rule matches.
+    pub fn webhook_payload(&self, rule: &Rule, user: &User) -> Option<String> {
+        match self {
+            Action::Webhook { template, .. } => Some(
+                serde_json::json!({
+                    "rule": rule.name,
+                    "criterion": rule.criterion.friendly(),
+                    "user": user,
+                    "timestamp": Utc::now().timestamp_millis(),
+                    "message": template
+                        .as_ref()
+                        .map(|t| render_template(t, &rule.name, &user.username)),
+                })
+                .to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Whether the action restricts the account, and so counts towards the hourly caps.
+    pub fn is_destructive(&self) -> bool {
+        matches!(
+            self,
+            Action::Shadowban
+                | Action::EngineMark
+                | Action::BoostMark
+                | Action::IpBan
+                | Action::Close
+                | Action::Alt
+                | Action::KidMode
+        )
+    }
+
+    pub fn delayed_by_default(&self) -> bool {
+        matches!(
+            self,
+            Action::EngineMark | Action::BoostMark | Action::IpBan | Action::Close
+        )
+    }
+}
+
+pub const DEFAULT_NOTE_TEMPLATE: &str = "Flagged by signup rule {rule}.";
+pub const DEFAULT_REPORT_TEMPLATE: &str = "Matched signup rule {rule}.";
+
+pub fn render_template(template: &str, rule: &str, username: &Username) -> String {
+    template
+        .replace("{rule}", rule)
+        .replace("{user}", &username.0)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ActionOutcome {
+    Done { status: u16 },
+    Failed { status: Option<u16>, reason: String },
 }
 
 pub fn expiry_loop(event_tx: Sender<Event>) {
@@ -332,3 +1264,88 @@ pub fn expiry_loop(event_tx: Sender<Event>) {
             .map_err(|e| println!("Err in periodically_...: {}", e))
     }));
 }
+
+pub fn reload_loop(event_tx: Sender<Event>) {
+    println!("Rules reload loop started.");
+    tokio::spawn(loop_fn((), move |_| {
+        let event_tx2 = event_tx.clone();
+        Delay::new(Instant::now() + std::time::Duration::from_secs(30))
+            .and_then(move |_| {
+                event_tx2.send(Event::InternalCheckRulesFile).unwrap();
+                Ok(Loop::Continue(()))
+            })
+            .map_err(|e| println!("Err in reload_loop: {}", e))
+    }));
+}
+
+pub fn stale_loop(event_tx: Sender<Event>) {
+    println!("Stale rules loop started.");
+    tokio::spawn(loop_fn((), move |_| {
+        let event_tx2 = event_tx.clone();
+        Delay::new(Instant::now() + std::time::Duration::from_secs(60 * 60))
+            .and_then(move |_| {
+                event_tx2.send(Event::InternalCheckStaleRules).unwrap();
+                Ok(Loop::Continue(()))
+            })
+            .map_err(|e| println!("Err in stale_loop: {}", e))
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signup::storage::JsonStorage;
+    use chrono::TimeZone;
+
+    fn manager(name: &str) -> (SignupRulesManager, String) {
+        let dir = std::env::temp_dir().join(format!("rules-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+        std::fs::write(format!("{}/rules.json", dir), "[]").unwrap();
+        let storage = JsonStorage::new(
+            format!("{}/rules.json", dir),
+            2,
+            format!("{}/audit.jsonl", dir),
+        );
+        let manager =
+            SignupRulesManager::new(Rc::new(storage), format!("{}/history.json", dir)).unwrap();
+        (manager, dir)
+    }
+
+    fn rule(name: &str, tag: &str) -> Rule {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "criterion": {"EmailContains": "wave"},
+            "actions": ["NotifyZulip"],
+            "tags": [tag],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tag_operations_save_once() {
+        let (mut manager, dir) = manager("tags");
+        for n in 0..6 {
+            manager
+                .add_rule(rule(&format!("wave{}", n), "wave"), "mod")
+                .unwrap();
+        }
+        manager.add_rule(rule("geo", "geo"), "mod").unwrap();
+
+        let expiry = Utc.with_ymd_and_hms(2026, 12, 31, 0, 0, 0).unwrap();
+        let renewed = manager.renew_rules(RuleSelection::Tag("wave".to_owned()), expiry, "mod");
+        assert_eq!(renewed.unwrap(), 6);
+        let removed = manager
+            .remove_rules(RuleSelection::Tag("wave".to_owned()), "mod")
+            .unwrap();
+        assert_eq!(removed.len(), 6);
+        assert_eq!(manager.rules().len(), 1);
+
+        let backup: Vec<Rule> =
+            serde_json::from_reader(std::fs::File::open(format!("{}/rules.json.1", dir)).unwrap())
+                .unwrap();
+        assert_eq!(backup.len(), 7);
+        assert!(backup[..6].iter().all(|r| r.expiry == Some(expiry)));
+    }
+}
@@ -0,0 +1,121 @@
+use crate::signup::rulefile::deserialize_date;
+
+use chrono::{serde::ts_milliseconds_option, DateTime, Datelike, Timelike, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// When a rule is active. All parts are optional and all of them have to hold:
+/// the day of the week, a range of UTC hours, and a one-off start time.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Schedule {
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    #[serde(default)]
+    pub hours: Option<HourRange>,
+    #[serde(
+        serialize_with = "ts_milliseconds_option::serialize",
+        deserialize_with = "deserialize_date",
+        default
+    )]
+    pub start: Option<DateTime<Utc>>,
+}
+
+/// UTC hours from `from` (inclusive) to `to` (exclusive). Wraps around midnight when
+/// `from` is larger than `to`, so `22-6` covers the night.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct HourRange {
+    pub from: u32,
+    pub to: u32,
+}
+
+impl HourRange {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.from <= self.to {
+            hour >= self.from && hour < self.to
+        } else {
+            hour >= self.from || hour < self.to
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        (self.days.is_empty() || self.days.contains(&now.weekday()))
+            && self.hours.is_none_or(|h| h.contains(now.hour()))
+            && self.start.is_none_or(|start| now >= start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.days.is_empty() && self.hours.is_none() && self.start.is_none()
+    }
+
+    pub fn friendly(&self) -> String {
+        let mut parts = vec![];
+        if !self.days.is_empty() {
+            parts.push(
+                self.days
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            );
+        }
+        if let Some(hours) = self.hours {
+            parts.push(format!("{}h-{}h UTC", hours.from, hours.to));
+        }
+        if let Some(start) = self.start {
+            parts.push(format!("from {}", start));
+        }
+        format!("Active: {}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        // 2026-06-01 is a Monday.
+        Utc.with_ymd_and_hms(2026, 6, day, hour, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn empty_schedule_is_always_active() {
+        let schedule = Schedule::default();
+        assert!(schedule.is_empty());
+        assert!(schedule.is_active_at(at(1, 0)));
+    }
+
+    #[test]
+    fn days_and_hours_both_have_to_hold() {
+        let schedule = Schedule {
+            days: vec![Weekday::Sat, Weekday::Sun],
+            hours: Some(HourRange { from: 9, to: 17 }),
+            start: None,
+        };
+        assert!(schedule.is_active_at(at(6, 9)));
+        assert!(schedule.is_active_at(at(7, 16)));
+        assert!(!schedule.is_active_at(at(6, 17)));
+        assert!(!schedule.is_active_at(at(1, 12)));
+    }
+
+    #[test]
+    fn hours_wrap_around_midnight() {
+        let night = HourRange { from: 22, to: 6 };
+        assert!(night.contains(22));
+        assert!(night.contains(0));
+        assert!(night.contains(5));
+        assert!(!night.contains(6));
+        assert!(!night.contains(12));
+    }
+
+    #[test]
+    fn start_is_inclusive() {
+        let schedule = Schedule {
+            start: Some(at(2, 0)),
+            ..Schedule::default()
+        };
+        assert!(!schedule.is_active_at(at(1, 23)));
+        assert!(schedule.is_active_at(at(2, 0)));
+    }
+}
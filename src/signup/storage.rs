@@ -0,0 +1,777 @@
+use crate::event::{User, Username};
+use crate::signup::audit::{AuditEntry, AuditQuery};
+use crate::signup::bundle::STATS_FIELDS;
+use crate::signup::rulefile;
+use crate::signup::rules::Rule;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{Map, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::Path,
+    rc::Rc,
+    time::SystemTime,
+};
+
+/// Where rules, their stats, the audit log and the recent signups are kept.
+pub trait Storage {
+    /// Loads the rules, along with a message for the moderators if loading needed attention
+    /// (a backup was used, or existing data was migrated).
+    fn load_rules(&self) -> Result<(Vec<Rule>, Option<String>), Box<dyn std::error::Error>>;
+
+    /// Loads the rules as they are stored right now, without falling back on anything, to
+    /// pick up changes made outside the bot.
+    fn reload_rules(&self) -> Result<Vec<Rule>, Box<dyn std::error::Error>>;
+
+    /// A value that changes whenever something other than this storage changes the stored
+    /// rules, if the backend can tell. Compare it to an earlier one to detect outside edits.
+    fn version(&self) -> Option<u128>;
+
+    /// Saves the rules after a definition changed. File backends keep the previous version
+    /// as a backup.
+    fn save_rules(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Saves the rules after only their stats changed, without rotating backups.
+    fn save_stats(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Saves the stats of `rules[index]` after it caught `username`.
+    fn save_match(
+        &self,
+        rules: &[Rule],
+        index: usize,
+        username: &Username,
+        date: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn record_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The latest `limit` audit entries matching `query`, oldest first.
+    fn search_audit(
+        &self,
+        query: &AuditQuery,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>>;
+
+    /// Remembers a signup, keeping only the latest `keep` ones.
+    fn record_signup(&self, user: &User, keep: usize) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The latest `limit` signups, oldest first.
+    fn recent_signups(&self, limit: usize) -> Result<Vec<User>, Box<dyn std::error::Error>>;
+}
+
+/// Opens the storage backend named in the configuration: `json`, `toml` or `sqlite`.
+pub fn open(
+    backend: &str,
+    rules_path: &str,
+    rules_backups: usize,
+    audit_log_path: &str,
+    rules_toml_path: &str,
+    rules_stats_path: &str,
+    sqlite_path: &str,
+) -> Result<Rc<dyn Storage>, Box<dyn std::error::Error>> {
+    let json = JsonStorage::new(
+        rules_path.to_owned(),
+        rules_backups,
+        audit_log_path.to_owned(),
+    );
+    match backend {
+        "json" => Ok(Rc::new(json)),
+        "toml" => Ok(Rc::new(TomlStorage::new(
+            rules_toml_path.to_owned(),
+            rules_backups,
+            rules_stats_path.to_owned(),
+            audit_log_path.to_owned(),
+            rules_path.to_owned(),
+        ))),
+        "sqlite" => Ok(Rc::new(SqliteStorage::open(sqlite_path, Some(&json))?)),
+        _ => Err(Box::new(std::io::Error::other(format!(
+            "Unknown storage backend `{}`.",
+            backend
+        )))),
+    }
+}
+
+/// Rules in one JSON file, the audit log as JSONL. Recent signups are only kept in memory.
+pub struct JsonStorage {
+    rules_path: String,
+    backups: usize,
+    audit_log_path: String,
+}
+
+impl JsonStorage {
+    pub fn new(rules_path: String, backups: usize, audit_log_path: String) -> Self {
+        JsonStorage {
+            rules_path,
+            backups,
+            audit_log_path,
+        }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load_rules(&self) -> Result<(Vec<Rule>, Option<String>), Box<dyn std::error::Error>> {
+        let e = match load_rules_file(&self.rules_path) {
+            Ok(rules) => return Ok((rules, None)),
+            Err(e) => e,
+        };
+        let (backup_path, rules) = (1..=self.backups)
+            .map(|n| backup_path(&self.rules_path, n))
+            .find_map(|path| load_rules_file(&path).ok().map(|rules| (path, rules)))
+            .ok_or(e.to_string())?;
+        // Keep the broken file around for inspection; the next save replaces it.
+        let corrupt_path = format!("{}.corrupt", &self.rules_path);
+        if let Err(e) = fs::rename(&self.rules_path, &corrupt_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(Box::new(e));
+            }
+        }
+        let warning = format!(
+            "**Warning**: could not load `{}` ({}), loaded {} rules from backup `{}` instead. \
+             The broken file was moved to `{}`.",
+            &self.rules_path,
+            e,
+            rules.len(),
+            backup_path,
+            corrupt_path
+        );
+        Ok((rules, Some(warning)))
+    }
+
+    fn reload_rules(&self) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+        load_rules_file(&self.rules_path)
+    }
+
+    fn version(&self) -> Option<u128> {
+        modified(&self.rules_path)
+    }
+
+    /// Writes the rules atomically. The previous version is kept as the newest of the
+    /// rotating backups.
+    fn save_rules(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        rotate_backups(&self.rules_path, self.backups)?;
+        self.save_stats(rules)
+    }
+
+    fn save_stats(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        write_atomically(&self.rules_path, &serde_json::to_vec(rules)?)
+    }
+
+    fn save_match(
+        &self,
+        rules: &[Rule],
+        _index: usize,
+        _username: &Username,
+        _date: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_stats(rules)
+    }
+
+    fn record_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>> {
+        append_audit_log(&self.audit_log_path, entry)
+    }
+
+    fn search_audit(
+        &self,
+        query: &AuditQuery,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+        search_audit_log(&self.audit_log_path, query, limit)
+    }
+
+    fn record_signup(&self, _user: &User, _keep: usize) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn recent_signups(&self, _limit: usize) -> Result<Vec<User>, Box<dyn std::error::Error>> {
+        Ok(vec![])
+    }
+}
+
+/// Writes to a temporary file, syncs it and renames it over `path`, so the file at `path`
+/// is never left half-written.
+pub fn write_atomically(path: &str, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_path = format!("{}.tmp", path);
+    {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = Path::new(path).parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_audit_log(path: &str) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let mut entries = vec![];
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+fn append_audit_log(path: &str, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>> {
+    let mut f = OpenOptions::new().append(true).create(true).open(path)?;
+    writeln!(f, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+fn search_audit_log(
+    path: &str,
+    query: &AuditQuery,
+    limit: usize,
+) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+    let mut found: Vec<AuditEntry> = read_audit_log(path)?
+        .into_iter()
+        .filter(|e| query.matches(e))
+        .collect();
+    let skip = found.len().saturating_sub(limit);
+    Ok(found.split_off(skip))
+}
+
+fn load_rules_file(path: &str) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+    let f = File::open(path)?;
+    Ok(serde_json::from_reader(f)?)
+}
+
+/// The modification time of the file at `path`, in nanoseconds since the epoch.
+fn modified(path: &str) -> Option<u128> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+}
+
+fn backup_path(rules_path: &str, n: usize) -> String {
+    format!("{}.{}", rules_path, n)
+}
+
+/// Shifts `path.1` … `path.{backups - 1}` up by one and copies `path` to `path.1`.
+fn rotate_backups(path: &str, backups: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if backups == 0 {
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        match fs::rename(backup_path(path, n), backup_path(path, n + 1)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Box::new(e)),
+            _ => {}
+        }
+    }
+    match fs::copy(path, backup_path(path, 1)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Rule definitions in a hand-editable TOML file, their stats in a JSON file next to it,
+/// and the audit log as JSONL. Recent signups are only kept in memory.
+pub struct TomlStorage {
+    definitions_path: String,
+    backups: usize,
+    stats_path: String,
+    audit_log_path: String,
+    /// A `rules.json` to convert if there is no TOML file yet.
+    migrate_from: String,
+}
+
+impl TomlStorage {
+    pub fn new(
+        definitions_path: String,
+        backups: usize,
+        stats_path: String,
+        audit_log_path: String,
+        migrate_from: String,
+    ) -> Self {
+        TomlStorage {
+            definitions_path,
+            backups,
+            stats_path,
+            audit_log_path,
+            migrate_from,
+        }
+    }
+
+    /// Reads the rule definitions in `path` and merges the stats into them.
+    fn load_definitions(&self, path: &str) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+        let rules = rulefile::from_toml(&fs::read_to_string(path)?)?;
+        let mut stats: Map<String, Value> = match File::open(&self.stats_path) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Map::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut merged = vec![];
+        for rule in rules {
+            match stats.remove(&rule.name) {
+                Some(Value::Object(fields)) => {
+                    let mut value = serde_json::to_value(&rule)?;
+                    if let Value::Object(ref mut value) = value {
+                        value.extend(fields);
+                    }
+                    merged.push(serde_json::from_value(value)?);
+                }
+                _ => merged.push(rule),
+            }
+        }
+        Ok(merged)
+    }
+
+    fn write_stats(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stats = Map::new();
+        for rule in rules {
+            let mut fields = Map::new();
+            if let Value::Object(mut value) = serde_json::to_value(rule)? {
+                for field in STATS_FIELDS.iter() {
+                    if let Some(v) = value.remove(*field) {
+                        fields.insert((*field).to_owned(), v);
+                    }
+                }
+            }
+            stats.insert(rule.name.clone(), Value::Object(fields));
+        }
+        write_atomically(&self.stats_path, &serde_json::to_vec(&stats)?)
+    }
+}
+
+impl Storage for TomlStorage {
+    fn load_rules(&self) -> Result<(Vec<Rule>, Option<String>), Box<dyn std::error::Error>> {
+        if !Path::new(&self.definitions_path).exists() {
+            if let Ok(rules) = load_rules_file(&self.migrate_from) {
+                self.save_rules(&rules)?;
+                let notice = format!(
+                    "Converted {} rules from `{}` to `{}`.",
+                    rules.len(),
+                    &self.migrate_from,
+                    &self.definitions_path
+                );
+                return Ok((rules, Some(notice)));
+            }
+        }
+        let e = match self.reload_rules() {
+            Ok(rules) => return Ok((rules, None)),
+            Err(e) => e,
+        };
+        let (backup_path, rules) = (1..=self.backups)
+            .map(|n| backup_path(&self.definitions_path, n))
+            .find_map(|path| self.load_definitions(&path).ok().map(|rules| (path, rules)))
+            .ok_or(e.to_string())?;
+        // Keep the broken file around for inspection, and put the backup in its place so
+        // that later saves keep its comments.
+        let corrupt_path = format!("{}.corrupt", &self.definitions_path);
+        if let Err(e) = fs::rename(&self.definitions_path, &corrupt_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(Box::new(e));
+            }
+        }
+        fs::copy(&backup_path, &self.definitions_path)?;
+        let warning = format!(
+            "**Warning**: could not load `{}` ({}), loaded {} rules from backup `{}` instead. \
+             The broken file was moved to `{}`.",
+            &self.definitions_path,
+            e,
+            rules.len(),
+            backup_path,
+            corrupt_path
+        );
+        Ok((rules, Some(warning)))
+    }
+
+    fn reload_rules(&self) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+        self.load_definitions(&self.definitions_path)
+    }
+
+    fn version(&self) -> Option<u128> {
+        modified(&self.definitions_path)
+    }
+
+    /// Only the rules whose definition changed are rewritten, so hand edits and comments
+    /// survive; if none did, the file is left alone. A file with errors is not saved over.
+    /// The previous rule file is kept as the newest of the rotating backups.
+    fn save_rules(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        let updated = match fs::read_to_string(&self.definitions_path) {
+            Ok(text) => rulefile::update_toml(&text, rules)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Some(rulefile::to_toml(rules)?),
+            Err(e) => return Err(Box::new(e)),
+        };
+        if let Some(text) = updated {
+            rotate_backups(&self.definitions_path, self.backups)?;
+            write_atomically(&self.definitions_path, text.as_bytes())?;
+        }
+        self.write_stats(rules)
+    }
+
+    fn save_stats(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_stats(rules)
+    }
+
+    fn save_match(
+        &self,
+        rules: &[Rule],
+        _index: usize,
+        _username: &Username,
+        _date: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_stats(rules)
+    }
+
+    fn record_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>> {
+        append_audit_log(&self.audit_log_path, entry)
+    }
+
+    fn search_audit(
+        &self,
+        query: &AuditQuery,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+        search_audit_log(&self.audit_log_path, query, limit)
+    }
+
+    fn record_signup(&self, _user: &User, _keep: usize) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn recent_signups(&self, _limit: usize) -> Result<Vec<User>, Box<dyn std::error::Error>> {
+        Ok(vec![])
+    }
+}
+
+/// Everything in one SQLite database. Rules and audit entries are stored as JSON, next to
+/// the columns needed to look them up, so new rule fields need no schema change.
+pub struct SqliteStorage {
+    conn: Connection,
+    migration_notice: Option<String>,
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) the database. The first time, if it has no rules and
+    /// `migrate_from` has some, those rules and their audit log are copied over.
+    pub fn open(
+        path: &str,
+        migrate_from: Option<&JsonStorage>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS rules (
+                 name TEXT PRIMARY KEY,
+                 position INTEGER NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS matches (
+                 id INTEGER PRIMARY KEY,
+                 rule TEXT NOT NULL,
+                 username TEXT NOT NULL,
+                 date INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS matches_rule ON matches (rule);
+             CREATE TABLE IF NOT EXISTS audit (
+                 id INTEGER PRIMARY KEY,
+                 date INTEGER NOT NULL,
+                 rule TEXT NOT NULL,
+                 username TEXT NOT NULL COLLATE NOCASE,
+                 data TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS audit_rule ON audit (rule);
+             CREATE INDEX IF NOT EXISTS audit_username ON audit (username);
+             CREATE TABLE IF NOT EXISTS signups (
+                 id INTEGER PRIMARY KEY,
+                 data TEXT NOT NULL
+             );",
+        )?;
+        let mut storage = SqliteStorage {
+            conn,
+            migration_notice: None,
+        };
+        if let Some(json) = migrate_from {
+            storage.migrate(json)?;
+        }
+        Ok(storage)
+    }
+
+    /// Copies the JSON rules and audit log over, once. The database remembers that in
+    /// `PRAGMA user_version`, so that removing every rule later doesn't bring back the JSON
+    /// ones, nor a second copy of the audit log.
+    fn migrate(&mut self, json: &JsonStorage) -> Result<(), Box<dyn std::error::Error>> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version >= MIGRATED_VERSION {
+            return Ok(());
+        }
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM rules", [], |row| row.get(0))?;
+        let tx = self.conn.unchecked_transaction()?;
+        if count == 0 && Path::new(&json.rules_path).exists() {
+            let rules = match load_rules_file(&json.rules_path) {
+                Ok(rules) => rules,
+                // Left for the next start, once the file is fixed.
+                Err(_) => return Ok(()),
+            };
+            let entries = read_audit_log(&json.audit_log_path)?;
+            insert_rules(&tx, &rules)?;
+            for entry in &entries {
+                insert_audit(&tx, entry)?;
+            }
+            self.migration_notice = Some(format!(
+                "Migrated {} rules from `{}` and {} audit entries from `{}` to SQLite.",
+                rules.len(),
+                &json.rules_path,
+                entries.len(),
+                &json.audit_log_path
+            ));
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {}", MIGRATED_VERSION))?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// The `user_version` of a database that no longer needs the JSON data migrated.
+const MIGRATED_VERSION: i64 = 1;
+
+fn insert_rules(conn: &Connection, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+    conn.execute("DELETE FROM rules", [])?;
+    for (position, rule) in rules.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO rules (name, position, data) VALUES (?1, ?2, ?3)",
+            params![&rule.name, position as i64, serde_json::to_string(rule)?],
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_audit(conn: &Connection, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>> {
+    conn.execute(
+        "INSERT INTO audit (date, rule, username, data) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            entry.date.timestamp_millis(),
+            &entry.rule,
+            &entry.username.0,
+            serde_json::to_string(entry)?
+        ],
+    )?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load_rules(&self) -> Result<(Vec<Rule>, Option<String>), Box<dyn std::error::Error>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT data FROM rules ORDER BY position")?;
+        let mut rules = vec![];
+        for data in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            rules.push(serde_json::from_str(&data?)?);
+        }
+        Ok((rules, self.migration_notice.clone()))
+    }
+
+    fn reload_rules(&self) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+        Ok(self.load_rules()?.0)
+    }
+
+    /// SQLite's data version, which only changes when another connection commits.
+    fn version(&self) -> Option<u128> {
+        self.conn
+            .query_row("PRAGMA data_version", [], |row| row.get::<_, i64>(0))
+            .ok()
+            .map(|v| v as u128)
+    }
+
+    fn save_rules(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        let tx = self.conn.unchecked_transaction()?;
+        insert_rules(&tx, rules)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_stats(&self, rules: &[Rule]) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_rules(rules)
+    }
+
+    fn save_match(
+        &self,
+        rules: &[Rule],
+        index: usize,
+        username: &Username,
+        date: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rule = &rules[index];
+        let tx = self.conn.unchecked_transaction()?;
+        let updated = tx.execute(
+            "UPDATE rules SET data = ?2 WHERE name = ?1",
+            params![&rule.name, serde_json::to_string(rule)?],
+        )?;
+        tx.execute(
+            "INSERT INTO matches (rule, username, date) VALUES (?1, ?2, ?3)",
+            params![&rule.name, &username.0, date.timestamp_millis()],
+        )?;
+        tx.commit()?;
+        if updated == 0 {
+            self.save_rules(rules)?;
+        }
+        Ok(())
+    }
+
+    fn record_audit(&self, entry: &AuditEntry) -> Result<(), Box<dyn std::error::Error>> {
+        insert_audit(&self.conn, entry)
+    }
+
+    fn search_audit(
+        &self,
+        query: &AuditQuery,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let (sql, value) = match query {
+            AuditQuery::All => (
+                "SELECT data FROM audit WHERE ?1 IS NULL ORDER BY id DESC LIMIT ?2",
+                None,
+            ),
+            AuditQuery::User(name) => (
+                "SELECT data FROM audit WHERE username = ?1 ORDER BY id DESC LIMIT ?2",
+                Some(name),
+            ),
+            AuditQuery::Rule(name) => (
+                "SELECT data FROM audit WHERE rule = ?1 ORDER BY id DESC LIMIT ?2",
+                Some(name),
+            ),
+        };
+        let mut stmt = self.conn.prepare(sql)?;
+        let mut found = vec![];
+        for data in stmt.query_map(params![value, limit], |row| row.get::<_, String>(0))? {
+            found.push(serde_json::from_str(&data?)?);
+        }
+        found.reverse();
+        Ok(found)
+    }
+
+    fn record_signup(&self, user: &User, keep: usize) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "INSERT INTO signups (data) VALUES (?1)",
+            params![serde_json::to_string(user)?],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.conn.execute(
+            "DELETE FROM signups WHERE id <= ?1",
+            params![id - keep as i64],
+        )?;
+        Ok(())
+    }
+
+    fn recent_signups(&self, limit: usize) -> Result<Vec<User>, Box<dyn std::error::Error>> {
+        let latest: Option<i64> = self
+            .conn
+            .query_row("SELECT MAX(id) FROM signups", [], |row| row.get(0))
+            .optional()?
+            .flatten();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT data FROM signups WHERE id > ?1 ORDER BY id")?;
+        let mut users = vec![];
+        for data in stmt.query_map(params![latest.unwrap_or(0) - limit as i64], |row| {
+            row.get::<_, String>(0)
+        })? {
+            users.push(serde_json::from_str(&data?)?);
+        }
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signup::audit::AuditRecord;
+    use crate::signup::rules::{Action, ActionOutcome};
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn rule(name: &str) -> Rule {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "criterion": {"EmailContains": "wave"},
+            "actions": ["NotifyZulip"],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn migrates_json_data_to_sqlite_only_once() {
+        let dir = temp_dir("migrate");
+        let json = JsonStorage::new(
+            format!("{}/rules.json", dir),
+            0,
+            format!("{}/audit.jsonl", dir),
+        );
+        json.save_rules(&[rule("a"), rule("b")]).unwrap();
+        json.record_audit(&AuditEntry {
+            date: Utc::now(),
+            rule: "a".to_owned(),
+            username: Username("user".to_owned()),
+            record: AuditRecord::Action {
+                action: Action::Close,
+                revert: false,
+                outcome: ActionOutcome::Done { status: 200 },
+            },
+        })
+        .unwrap();
+        let path = format!("{}/bot.sqlite", dir);
+
+        let sqlite = SqliteStorage::open(&path, Some(&json)).unwrap();
+        let (rules, notice) = sqlite.load_rules().unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(notice.is_some());
+        sqlite.save_rules(&[]).unwrap();
+        drop(sqlite);
+
+        let sqlite = SqliteStorage::open(&path, Some(&json)).unwrap();
+        let (rules, notice) = sqlite.load_rules().unwrap();
+        assert!(rules.is_empty());
+        assert!(notice.is_none());
+        assert_eq!(sqlite.search_audit(&AuditQuery::All, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn broken_rule_files_fall_back_to_a_backup() {
+        let dir = temp_dir("toml-backup");
+        let path = format!("{}/rules.toml", dir);
+        let toml = TomlStorage::new(
+            path.clone(),
+            2,
+            format!("{}/stats.json", dir),
+            format!("{}/audit.jsonl", dir),
+            format!("{}/rules.json", dir),
+        );
+        toml.save_rules(&[rule("a")]).unwrap();
+        toml.save_rules(&[rule("a"), rule("b")]).unwrap();
+        fs::write(&path, "[[rule]]\nname = \"a").unwrap();
+
+        assert!(toml.save_rules(&[rule("c")]).is_err());
+        let (rules, warning) = toml.load_rules().unwrap();
+        assert_eq!(rules.len(), 1);
+        assert!(warning.unwrap().contains("rules.toml.1"));
+        assert!(Path::new(&format!("{}.corrupt", path)).exists());
+        assert_eq!(toml.reload_rules().unwrap().len(), 1);
+    }
+}
@@ -1,18 +1,30 @@
+use crate::conf;
 use crate::event::{Event, Ip, User};
-use crate::signup::rules::{Action, Criterion, Rule};
+use crate::signup::audit::AuditQuery;
+use crate::signup::bundle::{ImportMode, RuleBundle};
+use crate::signup::escalation::{Escalation, EscalationKey};
+use crate::signup::rules::{
+    Action, ActionDelay, Criterion, DelayRange, Rule, RuleEdit, RuleSelection,
+    DEFAULT_NOTE_TEMPLATE, DEFAULT_REPORT_TEMPLATE,
+};
+use crate::signup::schedule::{HourRange, Schedule};
 
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc, Weekday};
 use regex::Regex;
 use serde_json;
 use std::error::Error;
 use std::sync::mpsc::Sender;
 
-pub fn handle_command(command: String, tx: Sender<Event>) -> Result<Option<String>, ParseError> {
+pub fn handle_command(
+    command: String,
+    author: String,
+    tx: Sender<Event>,
+) -> Result<Option<String>, ParseError> {
     let cmd = command.clone();
     let parts: Vec<&str> = cmd.split(" ").collect();
     match parts.get(0).ok_or(parse_error(None))? {
         &"status" => handle_status_command(tx.clone()),
-        &"signup" => handle_signup_command(command, tx.clone()),
+        &"signup" => handle_signup_command(command, author, tx.clone()),
         &"namechk" => handle_namechk_command(command, tx.clone()),
         _ => Err(parse_error(None)),
     }
@@ -49,7 +61,11 @@ fn handle_namechk_command(
     Ok(None)
 }
 
-fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<String>, ParseError> {
+fn handle_signup_command(
+    command: String,
+    author: String,
+    tx: Sender<Event>,
+) -> Result<Option<String>, ParseError> {
     let mut first_split: Vec<&str> = command.split("`").collect();
     let mut code = "";
     if first_split.len() > 2 {
@@ -60,7 +76,7 @@ fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<St
     }
     let code = code;
     let joined = first_split.join(" ");
-    let split: Vec<&str> = joined.split(" ").collect();
+    let split: Vec<&str> = split_args(&joined);
     let args: Vec<&&str> = split.iter().skip(1).collect();
     if !args.get(0).ok_or(parse_error(None))?.eq(&&"rules") {
         if args.get(0).ok_or(parse_error(None))?.eq(&&"seen") {
@@ -69,6 +85,15 @@ fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<St
             ))
             .unwrap();
             return Ok(None);
+        } else if args.get(0).ok_or(parse_error(None))?.eq(&&"queue") {
+            return handle_queue_command(args, tx);
+        } else if args.get(0).ok_or(parse_error(None))?.eq(&&"audit") {
+            return handle_audit_command(args, tx);
+        } else if args.get(0).ok_or(parse_error(None))?.eq(&&"breaker")
+            && args.get(1).eq(&Some(&&"reset"))
+        {
+            tx.send(Event::InternalResetBreaker).unwrap();
+            return Ok(None);
         } else {
             return Err(parse_error(None));
         }
@@ -86,70 +111,104 @@ fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<St
 
             let name: String = (***args.get(2).ok_or(parse_error(None))?).to_owned();
 
-            let criterion_element = args.get(4).ok_or(parse_error(None))?;
-            let criterion_check = args.get(5).ok_or(parse_error(None))?;
-            let criterion_value: String = (***args.get(6).ok_or(parse_error(None))?).to_owned();
+            let criterion = parse_criterion(
+                args.get(4).ok_or(parse_error(None))?,
+                args.get(5).ok_or(parse_error(None))?,
+                args.get(6).ok_or(parse_error(None))?,
+                code,
+            )?;
 
-            let criterion = match criterion_element {
-                &&"ip" => match criterion_check {
-                    &&"equals" => Criterion::IpMatch(Ip(criterion_value)),
-                    _ => return Err(parse_error(None)),
-                },
-                &&"print" => return Err(parse_error(Some("Use lichess print ban instead"))),
-                &&"email" => match criterion_check {
-                    &&"contains" => Criterion::EmailContains(criterion_value),
-                    &&"regex" => Criterion::EmailRegex(value_to_regex(&criterion_value)?),
-                    _ => return Err(parse_error(None)),
-                },
-                &&"username" => match criterion_check {
-                    &&"contains" => Criterion::UsernameContains(criterion_value),
-                    &&"regex" => Criterion::UsernameRegex(value_to_regex(&criterion_value)?),
-                    _ => return Err(parse_error(None)),
-                },
-                &&"useragent" => match criterion_check {
-                    &&"length-lte" => Criterion::UseragentLengthLte(criterion_value.parse()?),
-                    _ => return Err(parse_error(None)),
-                },
-                &&"lua" => Criterion::Lua(code.to_string()),
-                _ => return Err(parse_error(None)),
-            };
+            let (steps, action_delays) = parse_action_steps(args.get(8).ok_or(parse_error(None))?)?;
 
-            let actions: Vec<Action> = args
-                .get(8)
-                .ok_or(parse_error(None))?
-                .split("+")
-                .map(|one| match one {
-                    "shadowban" => Some(Action::Shadowban),
-                    "engine" => Some(Action::EngineMark),
-                    "boost" => Some(Action::BoostMark),
-                    "ipban" => Some(Action::IpBan),
-                    "close" => Some(Action::Close),
-                    "alt" => Some(Action::Alt),
-                    "panic" => Some(Action::EnableChatPanic),
-                    "notify" => Some(Action::NotifyZulip),
-                    _ => None,
-                })
-                .flatten()
-                .collect();
-
-            if actions.len() != args.get(8).ok_or(parse_error(None))?.split("+").count() {
-                return Err(parse_error(None));
+            let mut no_delay = false;
+            let mut delay = None;
+            let mut hourly_cap = None;
+            let mut escalate = None;
+            let mut expiry = match conf::DEFAULT_RULE_EXPIRY {
+                "none" => None,
+                default => Some(parse_expiry(default)?),
+            };
+            let mut auto_renew = false;
+            let mut auto_renew_period = None;
+            let mut note = None;
+            let mut tags = vec![];
+            let mut priority = 0;
+            let mut stop = false;
+            let mut schedule = Schedule::default();
+            let mut options = args.iter().skip(9).peekable();
+            while let Some(option) = options.next() {
+                match **option {
+                    "nodelay" => no_delay = true,
+                    "noexpiry" => expiry = None,
+                    "expires" => {
+                        expiry = Some(parse_expiry(
+                            options
+                                .next()
+                                .ok_or(parse_error(Some("Please provide an expiry")))?,
+                        )?)
+                    }
+                    "autorenew" => {
+                        auto_renew = true;
+                        if let Some(Ok(period)) = options.peek().map(|p| parse_expiry_duration(p)) {
+                            auto_renew_period = Some(period);
+                            options.next();
+                        }
+                    }
+                    "cap" => {
+                        hourly_cap = Some(
+                            options
+                                .next()
+                                .ok_or(parse_error(Some("Please provide an hourly cap")))?
+                                .parse()?,
+                        )
+                    }
+                    "delay" => {
+                        delay = Some(parse_delay_range(
+                            options
+                                .next()
+                                .ok_or(parse_error(Some("Please provide a delay range")))?,
+                        )?)
+                    }
+                    "escalate" => escalate = Some(parse_escalate(options.next(), options.next())?),
+                    "stop" => stop = true,
+                    "days" | "hours" | "starts" => {
+                        parse_schedule_option(option, options.next(), &mut schedule)?
+                    }
+                    "priority" => {
+                        priority = options
+                            .next()
+                            .ok_or(parse_error(Some("Please provide a priority")))?
+                            .parse()?
+                    }
+                    "tag" | "tags" => {
+                        tags = parse_tags(
+                            options
+                                .next()
+                                .ok_or(parse_error(Some("Please provide tags")))?,
+                        )?
+                    }
+                    "note" => {
+                        note = Some(parse_note(
+                            options
+                                .next()
+                                .ok_or(parse_error(Some("Please provide a note")))?,
+                        )?)
+                    }
+                    _ => return Err(parse_error(None)),
+                }
             }
 
-            let no_delay = match args.get(9) {
-                Some(s) => s == &&"nodelay",
-                None => false,
-            };
+            let (actions, escalation) = build_escalation(steps, escalate)?;
 
-            let expiry = match args.get(if no_delay { 10 } else { 9 }) {
-                Some(s) => {
-                    if s == &&"noexpiry" {
-                        None
-                    } else {
-                        Some(Utc::now() + Duration::days(182))
-                    }
+            let auto_renew = match (auto_renew, auto_renew_period, expiry) {
+                (false, _, _) => None,
+                (true, Some(period), _) => Some(period.num_seconds()),
+                (true, None, Some(expiry)) => Some((expiry - Utc::now()).num_seconds()),
+                (true, None, None) => {
+                    return Err(parse_error(Some(
+                        "Auto-renewal needs an expiry or a period, e.g. `autorenew 30d`",
+                    )))
                 }
-                None => Some(Utc::now() + Duration::days(182)),
             };
 
             let rule = Rule {
@@ -165,12 +224,30 @@ fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<St
                 exp_notification: 0,
                 creation_date: chrono::Utc::now(),
                 latest_match_date: None,
+                delay,
+                action_delays,
+                hourly_cap,
+                escalation,
+                revision: 0,
+                created_by: Some(author.clone()),
+                note,
+                tags,
+                priority,
+                stop,
+                schedule: if schedule.is_empty() {
+                    None
+                } else {
+                    Some(schedule)
+                },
+                auto_renew,
+                stale_since: None,
             };
 
-            tx.send(Event::InternalAddRule { rule }).unwrap();
+            tx.send(Event::InternalAddRule { rule, author }).unwrap();
 
             Ok(None)
         }
+        &&"edit" => handle_edit_command(args, code, author, tx),
         &&"show" => {
             tx.send(Event::InternalShowRule(
                 (***args.get(2).ok_or(parse_error(None))?).to_owned(),
@@ -180,47 +257,188 @@ fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<St
             Ok(None)
         }
         &&"remove" => {
-            tx.send(Event::InternalRemoveRule(
-                (***args.get(2).ok_or(parse_error(None))?).to_owned(),
-            ))
+            tx.send(Event::InternalRemoveRule {
+                name: (***args.get(2).ok_or(parse_error(None))?).to_owned(),
+                author,
+            })
             .unwrap();
 
             Ok(None)
         }
         &&"disable-re" => {
-            tx.send(Event::InternalDisableRules(
-                (***args.get(2).ok_or(parse_error(None))?).to_owned(),
-            ))
+            tx.send(Event::InternalDisableRules {
+                selection: RuleSelection::Pattern(
+                    (***args.get(2).ok_or(parse_error(None))?).to_owned(),
+                ),
+                author,
+            })
             .unwrap();
 
             Ok(None)
         }
         &&"enable-re" => {
-            tx.send(Event::InternalEnableRules(
-                (***args.get(2).ok_or(parse_error(None))?).to_owned(),
-            ))
+            tx.send(Event::InternalEnableRules {
+                selection: RuleSelection::Pattern(
+                    (***args.get(2).ok_or(parse_error(None))?).to_owned(),
+                ),
+                author,
+            })
+            .unwrap();
+
+            Ok(None)
+        }
+        &&"disable-tag" => {
+            tx.send(Event::InternalDisableRules {
+                selection: RuleSelection::Tag(parse_tag(args.get(2))?),
+                author,
+            })
+            .unwrap();
+
+            Ok(None)
+        }
+        &&"enable-tag" => {
+            tx.send(Event::InternalEnableRules {
+                selection: RuleSelection::Tag(parse_tag(args.get(2))?),
+                author,
+            })
+            .unwrap();
+
+            Ok(None)
+        }
+        &&"remove-tag" => {
+            tx.send(Event::InternalRemoveTag {
+                tag: parse_tag(args.get(2))?,
+                author,
+            })
             .unwrap();
 
             Ok(None)
         }
+        &&"renew-tag" => {
+            let tag = parse_tag(args.get(2))?;
+            let expiry_str = &(***args
+                .get(3)
+                .ok_or(parse_error(Some("Please provide a new expiry")))?);
+            tx.send(Event::InternalRenewTag {
+                tag,
+                new_expiry: parse_expiry(expiry_str)?,
+                author,
+            })
+            .unwrap();
+            Ok(None)
+        }
         &&"renew" => {
             let rule_name = (***args
                 .get(2)
                 .ok_or(parse_error(Some("Please provide a rule name")))?)
             .to_owned();
-            let duration_str = &(***args
+            let expiry_str = &(***args
                 .get(3)
                 .ok_or(parse_error(Some("Please provide a new expiry")))?);
-            let duration = parse_expiry_duration(duration_str)?;
             tx.send(Event::InternalRenewRule {
                 rule: rule_name,
-                new_expiry: Utc::now() + duration,
+                new_expiry: parse_expiry(expiry_str)?,
+                author,
             })
             .unwrap();
             Ok(None)
         }
+        &&"history" => {
+            tx.send(Event::InternalRuleHistory(
+                (***args
+                    .get(2)
+                    .ok_or(parse_error(Some("Please provide a rule name")))?)
+                .to_owned(),
+            ))
+            .unwrap();
+
+            Ok(None)
+        }
+        &&"restore" => {
+            let name = (***args
+                .get(2)
+                .ok_or(parse_error(Some("Please provide a rule name")))?)
+            .to_owned();
+            let revision = args
+                .get(3)
+                .ok_or(parse_error(Some("Please provide a revision")))?
+                .trim_start_matches('r')
+                .parse()?;
+            tx.send(Event::InternalRestoreRule {
+                name,
+                revision,
+                author,
+            })
+            .unwrap();
+
+            Ok(None)
+        }
+        &&"revert" => {
+            let rule_name = (***args
+                .get(2)
+                .ok_or(parse_error(Some("Please provide a rule name")))?)
+            .to_owned();
+            let confirmed = args.last().eq(&Some(&&"confirm"));
+            let since = match args.get(3) {
+                Some(&&"confirm") | None => None,
+                Some(duration_str) => Some(Utc::now() - parse_expiry_duration(duration_str)?),
+            };
+            tx.send(Event::InternalRevertRule {
+                rule: rule_name,
+                since,
+                confirmed,
+            })
+            .unwrap();
+            Ok(None)
+        }
+        &&"export" => {
+            let selection = match args.get(2) {
+                Some(s) if s.starts_with('#') => Some(RuleSelection::Tag(parse_tag(Some(s))?)),
+                Some(s) => Some(RuleSelection::Pattern((***s).to_owned())),
+                None => None,
+            };
+            tx.send(Event::InternalExportRules(selection)).unwrap();
+
+            Ok(None)
+        }
+        &&"import" => {
+            let (mode, mode_given) = match skip_words(&command, 3).split_whitespace().next() {
+                Some("skip") => (ImportMode::Skip, true),
+                Some("rename") => (ImportMode::Rename, true),
+                Some("overwrite") => (ImportMode::Overwrite, true),
+                _ => (ImportMode::Skip, false),
+            };
+            // The bundle is in a code block, or else follows the command.
+            let json = match code_block(&command) {
+                Some(block) => block,
+                None => skip_words(&command, if mode_given { 4 } else { 3 }),
+            };
+            if !json.trim_start().starts_with('{') {
+                return Err(parse_error(Some(
+                    "Please provide a rule bundle after `import [skip|rename|overwrite]`",
+                )));
+            }
+            let rules = RuleBundle::from_json(json)?.rules()?;
+            tx.send(Event::InternalImportRules {
+                rules,
+                mode,
+                author,
+            })
+            .unwrap();
+
+            Ok(None)
+        }
+        &&"reload" => {
+            tx.send(Event::InternalReloadRules).unwrap();
+
+            Ok(None)
+        }
         &&"list" => {
-            tx.send(Event::InternalListRules).unwrap();
+            let tag = match args.get(2) {
+                Some(_) => Some(parse_tag(args.get(2))?),
+                None => None,
+            };
+            tx.send(Event::InternalListRules(tag)).unwrap();
 
             Ok(None)
         }
@@ -234,6 +452,483 @@ fn handle_signup_command(command: String, tx: Sender<Event>) -> Result<Option<St
     }
 }
 
+fn parse_criterion(
+    criterion_element: &str,
+    criterion_check: &str,
+    criterion_value: &str,
+    code: &str,
+) -> Result<Criterion, ParseError> {
+    let criterion_value = criterion_value.to_owned();
+    Ok(match criterion_element {
+        "ip" => match criterion_check {
+            "equals" => Criterion::IpMatch(Ip(criterion_value)),
+            _ => return Err(parse_error(None)),
+        },
+        "print" => return Err(parse_error(Some("Use lichess print ban instead"))),
+        "email" => match criterion_check {
+            "contains" => Criterion::EmailContains(criterion_value),
+            "regex" => Criterion::EmailRegex(value_to_regex(&criterion_value)?),
+            _ => return Err(parse_error(None)),
+        },
+        "username" => match criterion_check {
+            "contains" => Criterion::UsernameContains(criterion_value),
+            "regex" => Criterion::UsernameRegex(value_to_regex(&criterion_value)?),
+            _ => return Err(parse_error(None)),
+        },
+        "useragent" => match criterion_check {
+            "length-lte" => Criterion::UseragentLengthLte(criterion_value.parse()?),
+            _ => return Err(parse_error(None)),
+        },
+        "lua" => Criterion::Lua(code.to_string()),
+        _ => return Err(parse_error(None)),
+    })
+}
+
+fn handle_edit_command(
+    args: Vec<&&str>,
+    code: &str,
+    author: String,
+    tx: Sender<Event>,
+) -> Result<Option<String>, ParseError> {
+    let name = (***args
+        .get(2)
+        .ok_or(parse_error(Some("Please provide a rule name")))?)
+    .to_owned();
+    let value = |i: usize| -> Result<&str, ParseError> {
+        Ok(**args
+            .get(i)
+            .ok_or(parse_error(Some("Please provide a new value")))?)
+    };
+
+    let edit = match value(3)? {
+        "criterion" => RuleEdit::Criterion(parse_criterion(value(4)?, value(5)?, value(6)?, code)?),
+        "actions" => {
+            let (steps, action_delays) = parse_action_steps(value(4)?)?;
+            let escalate = match args.get(5) {
+                Some(&&"escalate") => Some(parse_escalate(args.get(6), args.get(7))?),
+                Some(_) => return Err(parse_error(None)),
+                None => None,
+            };
+            let (actions, escalation) = build_escalation(steps, escalate)?;
+            RuleEdit::Actions {
+                actions,
+                action_delays,
+                escalation,
+            }
+        }
+        "expiry" => RuleEdit::Expiry(match value(4)? {
+            "none" | "noexpiry" => None,
+            expiry_str => Some(parse_expiry(expiry_str)?),
+        }),
+        "autorenew" => RuleEdit::AutoRenew(match value(4)? {
+            "off" | "false" | "no" => None,
+            period => Some(parse_expiry_duration(period)?.num_seconds()),
+        }),
+        "delay" => RuleEdit::Delay(match value(4)? {
+            "default" => None,
+            range => Some(parse_delay_range(range)?),
+        }),
+        "cap" => RuleEdit::HourlyCap(match value(4)? {
+            "default" => None,
+            cap => Some(cap.parse()?),
+        }),
+        "nodelay" => RuleEdit::NoDelay(parse_on_off(value(4)?)?),
+        "susp_ip" => RuleEdit::SuspIp(parse_on_off(value(4)?)?),
+        "priority" => RuleEdit::Priority(value(4)?.parse()?),
+        "stop" => RuleEdit::Stop(parse_on_off(value(4)?)?),
+        "schedule" => {
+            let mut schedule = Schedule::default();
+            if value(4)? != "none" {
+                let mut options = args.iter().skip(4);
+                while let Some(option) = options.next() {
+                    parse_schedule_option(option, options.next(), &mut schedule)?;
+                }
+            }
+            RuleEdit::Schedule(if schedule.is_empty() {
+                None
+            } else {
+                Some(schedule)
+            })
+        }
+        "tags" => RuleEdit::Tags(match value(4)? {
+            "none" => vec![],
+            tags => parse_tags(tags)?,
+        }),
+        "note" => RuleEdit::Note(match value(4)? {
+            "none" => None,
+            note => Some(parse_note(note)?),
+        }),
+        _ => {
+            return Err(parse_error(Some(
+                "Can edit: `criterion`, `actions`, `expiry`, `delay`, `cap`, `nodelay`, `susp_ip`, `note`, `tags`, `priority`, `stop`, `schedule`, `autorenew`",
+            )))
+        }
+    };
+
+    tx.send(Event::InternalEditRule { name, edit, author })
+        .unwrap();
+    Ok(None)
+}
+
+/// Tags are lowercase words like `wave-2026-10`, `geo` or `experimental`, optionally prefixed
+/// with `#`.
+fn parse_tag(s: Option<&&&str>) -> Result<String, ParseError> {
+    let tag = s
+        .ok_or(parse_error(Some("Please provide a tag")))?
+        .trim_start_matches('#')
+        .to_lowercase();
+    if tag.is_empty()
+        || !tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(parse_error(Some(
+            "Tags may only contain letters, digits, `-` and `_`",
+        )));
+    }
+    Ok(tag)
+}
+
+fn parse_tags(s: &str) -> Result<Vec<String>, ParseError> {
+    let mut tags: Vec<String> = vec![];
+    for part in s.split(',') {
+        let tag = parse_tag(Some(&&part))?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+/// A note is a quoted free-text rationale or ticket reference, e.g. `"see #1234"`.
+fn parse_note(s: &str) -> Result<String, ParseError> {
+    let note = s.trim_matches('"').trim();
+    if note.is_empty() {
+        Err(parse_error(Some("Please provide a note")))
+    } else {
+        Ok(note.to_owned())
+    }
+}
+
+/// Parses one part of a schedule: `days sat,sun`, `hours 22-6` (UTC) or
+/// `starts 2026-10-20T18:00`.
+fn parse_schedule_option(
+    option: &str,
+    value: Option<&&&str>,
+    schedule: &mut Schedule,
+) -> Result<(), ParseError> {
+    let value = **value.ok_or(parse_error(Some("Please provide a value")))?;
+    match option {
+        "days" => {
+            for day in value.split(',') {
+                schedule.days.push(
+                    day.parse::<Weekday>()
+                        .map_err(|_| parse_error(Some("Invalid day. Example: `days sat,sun`")))?,
+                );
+            }
+        }
+        "hours" => {
+            let (from, to) = value
+                .split_once('-')
+                .ok_or(parse_error(Some("Invalid hours. Example: `hours 22-6`")))?;
+            let (from, to): (u32, u32) = (from.parse()?, to.parse()?);
+            if from > 23 || to > 24 || from == to {
+                return Err(parse_error(Some("Invalid hours. Example: `hours 22-6`")));
+            }
+            schedule.hours = Some(HourRange { from, to });
+        }
+        "starts" => schedule.start = Some(parse_date_time(value)?),
+        _ => {
+            return Err(parse_error(Some(
+                "Schedules support `days`, `hours` and `starts`",
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// A UTC date, optionally with a time: `2026-12-31` or `2026-12-31T18:00`.
+fn parse_date_time(s: &str) -> Result<DateTime<Utc>, ParseError> {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap())
+        })
+        .map(|d| d.and_utc())
+        .map_err(|_| {
+            parse_error(Some(
+                "Invalid date. Example: `2026-12-31` or `2026-12-31T18:00` (UTC)",
+            ))
+        })
+}
+
+fn parse_on_off(s: &str) -> Result<bool, ParseError> {
+    match s {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(parse_error(Some("Please use `on` or `off`"))),
+    }
+}
+
+fn parse_escalate(
+    key: Option<&&&str>,
+    window: Option<&&&str>,
+) -> Result<(EscalationKey, i64), ParseError> {
+    let key = match key.map(|k| **k) {
+        Some("ip") => EscalationKey::Ip,
+        Some("fp") | Some("print") => EscalationKey::FingerPrint,
+        Some("domain") => EscalationKey::EmailDomain,
+        _ => {
+            return Err(parse_error(Some(
+                "Please provide an escalation key: `ip`, `fp` or `domain`",
+            )))
+        }
+    };
+    let window = parse_expiry_duration(
+        window.ok_or(parse_error(Some("Please provide an escalation window")))?,
+    )?;
+    if window.num_seconds() % 86400 != 0 {
+        return Err(parse_error(Some(
+            "Escalation windows are counted in whole days, e.g. `1d` or `2w`",
+        )));
+    }
+    Ok((key, window.num_days()))
+}
+
+fn handle_queue_command(args: Vec<&&str>, tx: Sender<Event>) -> Result<Option<String>, ParseError> {
+    match args.get(1).ok_or(parse_error(None))? {
+        &&"list" => {
+            tx.send(Event::InternalListQueue).unwrap();
+
+            Ok(None)
+        }
+        &&"cancel" => {
+            tx.send(Event::InternalCancelQueued(
+                (***args
+                    .get(2)
+                    .ok_or(parse_error(Some("Please provide a username")))?)
+                .to_owned(),
+            ))
+            .unwrap();
+
+            Ok(None)
+        }
+        _ => Err(parse_error(None)),
+    }
+}
+
+fn handle_audit_command(args: Vec<&&str>, tx: Sender<Event>) -> Result<Option<String>, ParseError> {
+    match args.get(1).ok_or(parse_error(None))? {
+        &&"export" => {
+            let query = match args.get(2) {
+                None => AuditQuery::All,
+                Some(_) => parse_audit_query(args.get(2), args.get(3))?,
+            };
+            tx.send(Event::InternalAuditExport(query)).unwrap();
+
+            Ok(None)
+        }
+        _ => {
+            tx.send(Event::InternalAuditSearch(parse_audit_query(
+                args.get(1),
+                args.get(2),
+            )?))
+            .unwrap();
+
+            Ok(None)
+        }
+    }
+}
+
+fn parse_audit_query(kind: Option<&&&str>, name: Option<&&&str>) -> Result<AuditQuery, ParseError> {
+    let name = (***name.ok_or(parse_error(Some("Please provide a name")))?).to_owned();
+    match kind.map(|k| **k) {
+        Some("user") => Ok(AuditQuery::User(name)),
+        Some("rule") => Ok(AuditQuery::Rule(name)),
+        _ => Err(parse_error(None)),
+    }
+}
+
+/// Parses escalation steps separated by `,`, each step being actions separated by `+`.
+fn parse_action_steps(s: &str) -> Result<(Vec<Vec<Action>>, Vec<ActionDelay>), ParseError> {
+    let mut steps = vec![];
+    let mut action_delays = vec![];
+    for step in split_outside_quotes(s, ',') {
+        let (actions, delays) = parse_actions(step)?;
+        steps.push(actions);
+        action_delays.extend(delays);
+    }
+    Ok((steps, action_delays))
+}
+
+fn build_escalation(
+    steps: Vec<Vec<Action>>,
+    escalate: Option<(EscalationKey, i64)>,
+) -> Result<(Vec<Action>, Option<Escalation>), ParseError> {
+    let mut steps = steps.into_iter();
+    let actions = steps.next().unwrap_or_default();
+    let steps: Vec<Vec<Action>> = steps.collect();
+    match (escalate, steps.is_empty()) {
+        (None, true) => Ok((actions, None)),
+        (Some((key, window_days)), false) => Ok((
+            actions,
+            Some(Escalation {
+                key,
+                window_days,
+                steps,
+            }),
+        )),
+        (Some(_), true) => Err(parse_error(Some(
+            "Please provide escalation steps separated by `,`, e.g. `notify,alt,ipban`",
+        ))),
+        (None, false) => Err(parse_error(Some(
+            "Escalation steps need an `escalate <ip|fp|domain> <window>` clause",
+        ))),
+    }
+}
+
+fn parse_actions(s: &str) -> Result<(Vec<Action>, Vec<ActionDelay>), ParseError> {
+    let mut actions = vec![];
+    let mut action_delays = vec![];
+    for one in split_outside_quotes(s, '+') {
+        let (action, delay) = parse_action(one)?;
+        if let Some(delay) = delay {
+            action_delays.push(ActionDelay {
+                action: action.clone(),
+                delay,
+            });
+        }
+        actions.push(action);
+    }
+    Ok((actions, action_delays))
+}
+
+/// Parses one action such as `engine`, `engine@1h-6h` or `note:"Wave {rule}"@5m-10m`.
+fn parse_action(s: &str) -> Result<(Action, Option<DelayRange>), ParseError> {
+    let (name, param, delay) = match s.split_once(":") {
+        Some((name, rest)) => {
+            let (param, delay) = match rest.strip_prefix('"') {
+                Some(quoted) => {
+                    let (param, after) = quoted
+                        .split_once('"')
+                        .ok_or(parse_error(Some("Unterminated quote in action")))?;
+                    if !after.is_empty() && !after.starts_with('@') {
+                        return Err(parse_error(None));
+                    }
+                    (param, after.strip_prefix('@'))
+                }
+                None => match rest.split_once("@") {
+                    Some((param, delay)) => (param, Some(delay)),
+                    None => (rest, None),
+                },
+            };
+            (name, Some(param.to_owned()), delay)
+        }
+        None => match s.split_once("@") {
+            Some((name, delay)) => (name, None, Some(delay)),
+            None => (s, None, None),
+        },
+    };
+
+    let action = match (name, param) {
+        ("shadowban", None) => Action::Shadowban,
+        ("engine", None) => Action::EngineMark,
+        ("boost", None) => Action::BoostMark,
+        ("ipban", None) => Action::IpBan,
+        ("close", None) => Action::Close,
+        ("alt", None) => Action::Alt,
+        ("panic", None) => Action::EnableChatPanic,
+        ("notify", None) => Action::NotifyZulip,
+        ("kid", None) => Action::KidMode,
+        ("note", template) => {
+            Action::Note(template.unwrap_or(DEFAULT_NOTE_TEMPLATE.to_owned()))
+        }
+        ("report", template) => {
+            Action::Report(template.unwrap_or(DEFAULT_REPORT_TEMPLATE.to_owned()))
+        }
+        ("warn", Some(subject)) => Action::Warn(subject),
+        ("webhook", Some(param)) => {
+            let (url, template) = match param.split_once(" ") {
+                Some((url, template)) => (url.to_owned(), Some(template.to_owned())),
+                None => (param, None),
+            };
+            match url.parse::<hyper::Uri>() {
+                Ok(uri) if uri.scheme_str() == Some("https") && uri.host().is_some() => {}
+                _ => {
+                    return Err(parse_error(Some(
+                        "Webhook URLs must be valid `https://` URLs",
+                    )))
+                }
+            }
+            Action::Webhook { url, template }
+        }
+        ("warn", None) => {
+            return Err(parse_error(Some(
+                "Please provide a warning subject, e.g. `warn:\"Warning: Accessing multiple accounts\"`",
+            )))
+        }
+        _ => return Err(parse_error(None)),
+    };
+    Ok((action, delay.map(parse_delay_range).transpose()?))
+}
+
+/// The contents of the first code block: fenced, without its language tag, or inline.
+fn code_block(command: &str) -> Option<&str> {
+    if let Some((_, rest)) = command.split_once("```") {
+        let (block, _) = rest.split_once("```")?;
+        return Some(match block.split_once('\n') {
+            Some((tag, body)) if tag.chars().all(|c| c.is_ascii_alphanumeric()) => body,
+            _ => block,
+        });
+    }
+    let (_, rest) = command.split_once('`')?;
+    rest.split_once('`').map(|(block, _)| block)
+}
+
+/// What is left of `command` after its first `n` words.
+fn skip_words(command: &str, n: usize) -> &str {
+    (0..n).fold(command.trim_start(), |rest, _| {
+        rest.trim_start_matches(|c: char| !c.is_whitespace())
+            .trim_start()
+    })
+}
+
+/// Splits a command on spaces. Only action lists and the values of `edit` may hold quoted
+/// text with spaces, like `Note:"Wave of {rule}"`; criteria are split as they are, quotes
+/// included.
+fn split_args(command: &str) -> Vec<&str> {
+    let plain: Vec<&str> = command.split(' ').collect();
+    let quoted_from = match (plain.get(1), plain.get(2), plain.get(4)) {
+        (Some(&"rules"), Some(&"add"), _) => 9,
+        (Some(&"rules"), Some(&"edit"), Some(&"criterion")) => plain.len(),
+        (Some(&"rules"), Some(&"edit"), _) => 5,
+        _ => plain.len(),
+    };
+    if quoted_from >= plain.len() {
+        return plain;
+    }
+    let offset: usize = plain[..quoted_from].iter().map(|p| p.len() + 1).sum();
+    let mut split = plain[..quoted_from].to_vec();
+    split.extend(split_outside_quotes(&command[offset..], ' '));
+    split
+}
+
+/// Splits `s` on `sep`, except within double quotes.
+fn split_outside_quotes(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
 fn value_to_regex(v: &str) -> Result<Regex, regex::Error> {
     if v.starts_with("(?i)") {
         Regex::new(v)
@@ -242,24 +937,80 @@ fn value_to_regex(v: &str) -> Result<Regex, regex::Error> {
     }
 }
 
+/// A duration of hours (`h`), days (`d`), weeks (`w`) or 30-day months (`m`, or `mo`).
 fn parse_expiry_duration(s: &str) -> Result<Duration, ParseError> {
-    let step = s.chars().last().unwrap_or('/');
-    let mut arg = s.chars();
-    arg.next_back();
-    let amount = arg.as_str().parse::<u32>().unwrap_or(0);
-    if amount == 0 || (step != 'd' && step != 'w') {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(unit_start);
+    let amount = amount.parse::<u32>().unwrap_or(0);
+    if amount == 0 || !["h", "d", "w", "m", "mo"].contains(&unit) {
         return Err(parse_error(Some(
-            "Invalid expiry date format. Example: `14d`. Supported: `d` (day), `w` (week).",
+            "Invalid expiry date format. Example: `14d`. Supported: `h` (hour), `d` (day), `w` (week), `m` (month).",
         )));
     }
 
-    match step {
-        'd' => Ok(chrono::Duration::days(amount.into())),
-        'w' => Ok(chrono::Duration::weeks(amount.into())),
+    match unit {
+        "h" => Ok(chrono::Duration::hours(amount.into())),
+        "d" => Ok(chrono::Duration::days(amount.into())),
+        "w" => Ok(chrono::Duration::weeks(amount.into())),
+        "m" | "mo" => Ok(chrono::Duration::days(30 * i64::from(amount))),
         _ => unreachable!(),
     }
 }
 
+/// An expiry is either a duration from now (`6h`, `14d`, `3m`) or an absolute UTC date
+/// (`2026-12-31`).
+fn parse_expiry(s: &str) -> Result<DateTime<Utc>, ParseError> {
+    if s.contains('-') {
+        let expiry = parse_date_time(s)?;
+        if expiry <= Utc::now() {
+            return Err(parse_error(Some("The expiry date is in the past")));
+        }
+        Ok(expiry)
+    } else {
+        Ok(Utc::now() + parse_expiry_duration(s)?)
+    }
+}
+
+fn parse_delay_range(s: &str) -> Result<DelayRange, ParseError> {
+    let (min, max) = s.split_once("-").unwrap_or((s, s));
+    let range = DelayRange {
+        min_secs: parse_delay_duration(min)?,
+        max_secs: parse_delay_duration(max)?,
+    };
+    if range.min_secs > range.max_secs {
+        return Err(parse_error(Some(
+            "Invalid delay range: the minimum is larger than the maximum.",
+        )));
+    }
+    Ok(range)
+}
+
+fn parse_delay_duration(s: &str) -> Result<u64, ParseError> {
+    let step = s.chars().last().unwrap_or('/');
+    let mut arg = s.chars();
+    arg.next_back();
+    let amount = arg.as_str().parse::<u64>().map_err(|_| {
+        parse_error(Some(
+            "Invalid delay format. Example: `5m-2h`. Supported: `s` (second), `m` (minute), `h` (hour), `d` (day).",
+        ))
+    })?;
+
+    let unit = match step {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => {
+            return Err(parse_error(Some(
+                "Invalid delay format. Example: `5m-2h`. Supported: `s` (second), `m` (minute), `h` (hour), `d` (day).",
+            )))
+        }
+    };
+    amount
+        .checked_mul(unit)
+        .ok_or_else(|| parse_error(Some("Delay too long.")))
+}
+
 #[derive(Debug)]
 pub struct ParseError {
     pub message: String,
@@ -306,3 +1057,108 @@ impl From<serde_json::Error> for ParseError {
         parse_error(Some("Can't (de)serialize"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_durations() {
+        assert_eq!(parse_delay_duration("30s").unwrap(), 30);
+        assert_eq!(parse_delay_duration("5m").unwrap(), 300);
+        assert_eq!(parse_delay_duration("2h").unwrap(), 7200);
+        assert_eq!(parse_delay_duration("1d").unwrap(), 86400);
+        assert!(parse_delay_duration("5").is_err());
+        assert!(parse_delay_duration("m").is_err());
+        assert!(parse_delay_duration("5mo").is_err());
+        assert_eq!(
+            parse_delay_duration("18446744073709551615d")
+                .unwrap_err()
+                .message,
+            "Delay too long."
+        );
+    }
+
+    #[test]
+    fn delay_ranges() {
+        let range = parse_delay_range("5m-2h").unwrap();
+        assert_eq!((range.min_secs, range.max_secs), (300, 7200));
+        let fixed = parse_delay_range("10s").unwrap();
+        assert_eq!((fixed.min_secs, fixed.max_secs), (10, 10));
+        assert!(parse_delay_range("2h-5m").is_err());
+    }
+
+    #[test]
+    fn quotes_only_group_actions_and_edit_values() {
+        assert_eq!(
+            split_outside_quotes(r#"Note:"Wave of {rule}",Close"#, ','),
+            vec![r#"Note:"Wave of {rule}""#, "Close"]
+        );
+        assert_eq!(
+            split_args(r#"signup rules add wave if email regex "a b" then Note:"x y" 7d"#),
+            vec![
+                "signup",
+                "rules",
+                "add",
+                "wave",
+                "if",
+                "email",
+                "regex",
+                "\"a",
+                "b\"",
+                "then",
+                "Note:\"x y\"",
+                "7d",
+            ]
+        );
+        assert_eq!(
+            split_args(r#"signup rules edit wave note "two words""#),
+            vec!["signup", "rules", "edit", "wave", "note", "\"two words\""]
+        );
+        assert_eq!(
+            split_args(r#"signup rules edit wave criterion "a b""#),
+            vec!["signup", "rules", "edit", "wave", "criterion", "\"a", "b\""]
+        );
+        assert_eq!(
+            split_args(r#"signup check "a b""#),
+            vec!["signup", "check", "\"a", "b\""]
+        );
+    }
+
+    #[test]
+    fn expiry_durations() {
+        assert_eq!(parse_expiry_duration("6h").unwrap(), Duration::hours(6));
+        assert_eq!(parse_expiry_duration("14d").unwrap(), Duration::days(14));
+        assert_eq!(parse_expiry_duration("2w").unwrap(), Duration::weeks(2));
+        assert_eq!(parse_expiry_duration("6mo").unwrap(), Duration::days(180));
+        assert_eq!(parse_expiry_duration("6m").unwrap(), Duration::days(180));
+        for invalid in &["", "d", "0d", "5y", "5", "-5d", "5 d"] {
+            assert!(parse_expiry_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn expiry_dates_have_to_be_in_the_future() {
+        assert!(parse_expiry("2000-01-01").is_err());
+        assert!(parse_expiry("14d").unwrap() > Utc::now());
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(code_block("import\n```json\n{}\n```"), Some("{}\n"));
+        assert_eq!(code_block("import\n```\n{}\n```"), Some("{}\n"));
+        assert_eq!(code_block("import ```{\"a\": 1}```"), Some("{\"a\": 1}"));
+        assert_eq!(code_block("import `{}` please"), Some("{}"));
+        assert_eq!(code_block("import {}"), None);
+        assert_eq!(code_block("import ```{}"), None);
+    }
+
+    #[test]
+    fn skipping_words() {
+        let command = "@bot signup rules import  overwrite\n{}";
+        assert_eq!(skip_words(command, 3), "import  overwrite\n{}");
+        assert_eq!(skip_words(command, 4), "overwrite\n{}");
+        assert_eq!(skip_words(command, 5), "{}");
+        assert_eq!(skip_words(command, 9), "");
+    }
+}
@@ -133,8 +133,14 @@ pub fn connect_to_zulip(
                                                             listen_topic.to_owned(),
                                                         ))
                                                 {
+                                                    let author = message
+                                                        .get("sender_full_name")
+                                                        .and_then(|n| n.as_str())
+                                                        .unwrap_or("unknown")
+                                                        .to_owned();
                                                     let text_reply = match handle_command(
                                                         text[bot_ping2.len()..].to_owned(),
+                                                        author,
                                                         tx2.clone(),
                                                     ) {
                                                         Ok(s) => s,
@@ -2,7 +2,7 @@ use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
 use futures::future;
 use hyper::header::HeaderValue;
-use hyper::rt::Future;
+use hyper::rt::{Future, Stream};
 use hyper::{Body, Client, Method, Request};
 use hyper_rustls::HttpsConnector;
 use tokio;
@@ -51,3 +51,72 @@ pub fn post_message(
             .map_err(|err| println!("Error in post_message: {}", err))
     }));
 }
+
+/// Uploads `content` as a file and posts a message linking to it, prefixed by `text`.
+pub fn upload_file(
+    text: String,
+    file_name: String,
+    content: Vec<u8>,
+    bot_id: &'static str,
+    token: &'static str,
+    stream: &'static str,
+    topic: &'static str,
+    zulip_url: &'static str,
+) {
+    tokio::spawn(future::lazy(move || {
+        let https = HttpsConnector::new(2);
+        let client = Client::builder().build::<_, Body>(https);
+
+        let boundary = "lichess-event-stream-upload-boundary";
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            boundary, file_name
+        )
+        .into_bytes();
+        body.extend(content);
+        body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+
+        let mut req = Request::new(Body::from(body));
+        *req.uri_mut() = format!("https://{}/api/v1/user_uploads", zulip_url)
+            .parse()
+            .unwrap();
+        *req.method_mut() = Method::POST;
+        req.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={}", boundary))
+                .expect("Content-Type header value error"),
+        );
+        req.headers_mut().insert(
+            hyper::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!(
+                "Basic {}",
+                BASE64.encode(bot_id.to_owned() + ":" + token)
+            ))
+            .expect("Authorization header value error"),
+        );
+
+        client
+            .request(req)
+            .and_then(|res| res.into_body().concat2())
+            .map(move |body| {
+                let resp: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+                let link = resp
+                    .get("url")
+                    .or_else(|| resp.get("uri"))
+                    .and_then(|u| u.as_str());
+                post_message(
+                    match link {
+                        Some(link) => format!("{} [{}]({})", text, file_name, link),
+                        None => format!("{} Upload of `{}` failed: {}", text, file_name, resp),
+                    },
+                    bot_id,
+                    token,
+                    stream,
+                    topic,
+                    zulip_url,
+                );
+            })
+            .map_err(|err| println!("Error in upload_file: {}", err))
+    }));
+}